@@ -0,0 +1,573 @@
+use crate::{configuration::Configuration, logging::log_error, utilities::percent_decode};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use matrix_sdk::{
+    room,
+    ruma::{
+        api::{
+            appservice::{Namespace, Namespaces, Registration, RegistrationInit},
+            client::{
+                account::register::{self, LoginType},
+                error::ErrorKind,
+                membership::joined_rooms,
+                message::send_message_event,
+                state::get_state_events_for_key,
+                sync::sync_events,
+                uiaa::UiaaResponse,
+            },
+            error::{FromHttpResponseError, ServerError},
+        },
+        events::{
+            room::{member::MembershipState, message::RoomMessageEventContent},
+            AnyStateEvent, AnyTimelineEvent, StateEventType,
+        },
+        serde::Raw,
+        OwnedRoomId, OwnedUserId, ServerName, TransactionId, UserId,
+    },
+    Client, HttpError, RumaApiError, Session,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+pub fn load_registration(path: &Path) -> Result<Registration, String> {
+    let file_contents = match std::fs::read_to_string(path) {
+        Ok(file_contents) => file_contents,
+        Err(error) => {
+            return match error.kind() {
+                std::io::ErrorKind::NotFound => Err("file not found".to_string()),
+                _ => Err(format!("unable to read file: {error}")),
+            }
+        }
+    };
+    match serde_yaml::from_str(&file_contents) {
+        Ok(registration) => Ok(registration),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+pub fn save_registration(registration: &Registration, path: &Path) -> Result<(), String> {
+    match std::fs::write(
+        path,
+        serde_yaml::to_string(registration).unwrap().as_bytes(),
+    ) {
+        Ok(_) => Ok(()),
+        Err(error) => Err(format!("unable to save file: {error}")),
+    }
+}
+
+fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(64)
+        .map(char::from)
+        .collect()
+}
+
+fn escape_regex(string: &str) -> String {
+    let mut escaped = String::new();
+    for character in string.chars() {
+        if "\\.+*?()|[]{}^$#&-~".contains(character) {
+            escaped.push('\\');
+        }
+        escaped.push(character);
+    }
+    escaped
+}
+
+/// Creates a registration with fresh tokens that reserves the ghost
+/// user namespace (and matching aliases) for the bridge.
+pub fn generate_registration(
+    bot_configuration: &Configuration,
+    sender_localpart: &str,
+) -> Result<Registration, String> {
+    check_ghost_user_prefix(bot_configuration)?;
+    let prefix = escape_regex(&bot_configuration.ghost_user_prefix);
+    let mut namespaces = Namespaces::new();
+    namespaces
+        .users
+        .push(Namespace::new(true, format!("@{prefix}.*:.*")));
+    namespaces
+        .aliases
+        .push(Namespace::new(true, format!("#{prefix}.*:.*")));
+    Ok(RegistrationInit {
+        id: "netchat_bridge".to_string(),
+        url: bot_configuration.appservice_url.to_string(),
+        as_token: generate_token(),
+        hs_token: generate_token(),
+        sender_localpart: sender_localpart.to_string(),
+        namespaces,
+        rate_limited: Some(false),
+        protocols: Some(vec!["netchat".to_string()]),
+    }
+    .into())
+}
+
+/// Without a prefix, the ghost namespace would cover every user on the homeserver.
+pub fn check_ghost_user_prefix(bot_configuration: &Configuration) -> Result<(), String> {
+    if bot_configuration.ghost_user_prefix.is_empty() {
+        return Err("ghost_user_prefix must not be empty".to_string());
+    }
+    Ok(())
+}
+
+/// The bot's own user ID, where a bare username is assumed
+/// to live on the homeserver's host.
+pub fn bot_user_id(username: &str, homeserver_url: &str) -> Result<OwnedUserId, String> {
+    if username.starts_with('@') {
+        return match UserId::parse(username) {
+            Ok(user_id) => Ok(user_id),
+            Err(error) => Err(format!("invalid user ID: {error}")),
+        };
+    }
+    let host = match reqwest::Url::parse(homeserver_url) {
+        Ok(url) => match url.host_str() {
+            Some(host) => host.to_string(),
+            None => return Err("homeserver URL has no host".to_string()),
+        },
+        Err(error) => return Err(format!("invalid homeserver URL: {error}")),
+    };
+    let server_name = match <&ServerName>::try_from(host.as_str()) {
+        Ok(server_name) => server_name,
+        Err(error) => return Err(format!("invalid server name: {error}")),
+    };
+    match UserId::parse_with_server_name(username, server_name) {
+        Ok(user_id) => Ok(user_id),
+        Err(error) => Err(format!("invalid user ID: {error}")),
+    }
+}
+
+/// Whether a Matrix user is one of the bridge's NetChat ghosts.
+pub fn is_ghost(bot_configuration: &Configuration, user_id: &UserId) -> bool {
+    bot_configuration.appservice_mode
+        && !bot_configuration.ghost_user_prefix.is_empty()
+        && user_id
+            .localpart()
+            .starts_with(&bot_configuration.ghost_user_prefix)
+}
+
+/// Turns a NetChat username into a valid Matrix localpart. Uppercase letters
+/// become `_` followed by the lowercase letter and anything else outside the
+/// allowed characters is written as `=` and its hex value, so different
+/// usernames never end up sharing a ghost.
+pub fn ghost_localpart(prefix: &str, netchat_username: &str) -> String {
+    let mut localpart = prefix.to_string();
+    for byte in netchat_username.bytes() {
+        match byte {
+            b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'/' => localpart.push(byte as char),
+            b'A'..=b'Z' => {
+                localpart.push('_');
+                localpart.push(byte.to_ascii_lowercase() as char);
+            }
+            b'_' => localpart.push_str("__"),
+            _ => localpart.push_str(&format!("={byte:02x}")),
+        }
+    }
+    localpart
+}
+
+#[derive(Default)]
+struct GhostState {
+    clients: HashMap<OwnedUserId, Client>,
+    joined_rooms: HashSet<(OwnedUserId, OwnedRoomId)>,
+}
+
+/// Puppets one Matrix user per NetChat username through the
+/// application service's `as_token` and identity assertion.
+#[derive(Clone)]
+pub struct GhostUsers {
+    homeserver_url: String,
+    as_token: String,
+    user_prefix: String,
+    state: Arc<Mutex<GhostState>>,
+}
+
+impl GhostUsers {
+    pub fn new(homeserver_url: &str, as_token: &str, user_prefix: &str) -> Self {
+        Self {
+            homeserver_url: homeserver_url.to_string(),
+            as_token: as_token.to_string(),
+            user_prefix: user_prefix.to_string(),
+            state: Arc::new(Mutex::new(GhostState::default())),
+        }
+    }
+
+    async fn ghost_client(
+        &self,
+        netchat_username: &str,
+        bot_room: &room::Joined,
+    ) -> Result<(OwnedUserId, Client), String> {
+        let server_name = bot_room
+            .client()
+            .user_id()
+            .unwrap()
+            .server_name()
+            .to_owned();
+        let user_id = match UserId::parse(format!(
+            "@{}:{server_name}",
+            ghost_localpart(&self.user_prefix, netchat_username)
+        )) {
+            Ok(user_id) => user_id,
+            Err(error) => return Err(format!("invalid ghost user ID: {error}")),
+        };
+        if let Some(client) = self.state.lock().unwrap().clients.get(&user_id) {
+            return Ok((user_id, client.clone()));
+        }
+
+        let client = match Client::builder()
+            .homeserver_url(&self.homeserver_url)
+            .appservice_mode()
+            .assert_identity()
+            .build()
+            .await
+        {
+            Ok(client) => client,
+            Err(error) => return Err(format!("unable to build ghost client: {error}")),
+        };
+        if let Err(error) = client
+            .restore_login(Session {
+                access_token: self.as_token.to_string(),
+                refresh_token: None,
+                user_id: user_id.clone(),
+                device_id: "NETCHATBRIDGE".into(),
+            })
+            .await
+        {
+            return Err(format!("unable to set up ghost session: {error}"));
+        }
+
+        let mut request = register::v3::Request::new();
+        request.username = Some(user_id.localpart());
+        request.login_type = Some(&LoginType::ApplicationService);
+        request.inhibit_login = true;
+        match client.register(request).await {
+            Ok(_) => (),
+            Err(HttpError::UiaaError(FromHttpResponseError::Server(ServerError::Known(
+                UiaaResponse::MatrixError(error),
+            )))) if error.kind == ErrorKind::UserInUse => (),
+            Err(error) => return Err(format!("unable to register ghost: {error}")),
+        }
+        if let Err(error) = client
+            .account()
+            .set_display_name(Some(netchat_username))
+            .await
+        {
+            log_error(error);
+        }
+
+        self.state
+            .lock()
+            .unwrap()
+            .clients
+            .insert(user_id.clone(), client.clone());
+        Ok((user_id, client))
+    }
+
+    /// Sends a message into a bridged room as the ghost of a NetChat user,
+    /// inviting and joining the ghost first if it isn't a member yet.
+    pub async fn send_message(
+        &self,
+        bot_room: &room::Joined,
+        netchat_username: &str,
+        content: &RoomMessageEventContent,
+    ) -> Result<(), String> {
+        let (user_id, client) = self.ghost_client(netchat_username, bot_room).await?;
+        let membership = (user_id.clone(), bot_room.room_id().to_owned());
+        if !self
+            .state
+            .lock()
+            .unwrap()
+            .joined_rooms
+            .contains(&membership)
+        {
+            // Fails harmlessly if the ghost is already in the room.
+            let _ = bot_room.invite_user_by_id(&user_id).await;
+            if let Err(error) = client.join_room_by_id(bot_room.room_id()).await {
+                return Err(format!("unable to join ghost to room: {error}"));
+            }
+            self.state.lock().unwrap().joined_rooms.insert(membership);
+        }
+
+        let transaction_id = TransactionId::new();
+        let request = match send_message_event::v3::Request::new(
+            bot_room.room_id(),
+            &transaction_id,
+            content,
+        ) {
+            Ok(request) => request,
+            Err(error) => return Err(format!("unable to serialize message: {error}")),
+        };
+        match client.send(request, None).await {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!("unable to send message as ghost: {error}")),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Transaction {
+    events: Vec<Raw<AnyTimelineEvent>>,
+}
+
+/// Converts a transaction pushed by the homeserver into the sync response
+/// the client expects, so the usual event handlers run for it.
+fn transaction_to_sync_response(
+    bot_user_id: &UserId,
+    transaction_id: &str,
+    events: Vec<Raw<AnyTimelineEvent>>,
+) -> sync_events::v3::Response {
+    let mut response = sync_events::v3::Response::new(transaction_id.to_string());
+    for raw_event in events {
+        let event = match raw_event.deserialize() {
+            Ok(event) => event,
+            Err(error) => {
+                log_error(error);
+                continue;
+            }
+        };
+        let room_id = event.room_id().to_owned();
+        if let AnyTimelineEvent::State(AnyStateEvent::RoomMember(member)) = &event {
+            if member.state_key() == bot_user_id && *member.membership() == MembershipState::Invite
+            {
+                response
+                    .rooms
+                    .invite
+                    .entry(room_id)
+                    .or_default()
+                    .invite_state
+                    .events
+                    .push(raw_event.cast());
+                continue;
+            }
+        }
+        response
+            .rooms
+            .join
+            .entry(room_id)
+            .or_default()
+            .timeline
+            .events
+            .push(raw_event.cast());
+    }
+    response
+}
+
+/// Makes the rooms the bot is already in known to the client. An
+/// appservice doesn't sync, so it would otherwise only learn about
+/// them once the homeserver pushes the first event from each one.
+pub async fn load_joined_rooms(client: &Client) -> Result<(), String> {
+    let joined_rooms = match client.send(joined_rooms::v3::Request::new(), None).await {
+        Ok(response) => response.joined_rooms,
+        Err(error) => return Err(format!("unable to get joined rooms: {error}")),
+    };
+    let transaction_id = TransactionId::new();
+    let mut response = sync_events::v3::Response::new(transaction_id.to_string());
+    for room_id in joined_rooms {
+        response.rooms.join.entry(room_id).or_default();
+    }
+    match client.receive_transaction(&transaction_id, response).await {
+        Ok(_) => Ok(()),
+        Err(error) => Err(format!("unable to load joined rooms: {error}")),
+    }
+}
+
+/// Asks the homeserver whether a room is end-to-end encrypted. Rooms are
+/// loaded without their state, so the client doesn't know about an
+/// `m.room.encryption` event that was sent before the bot joined.
+pub async fn is_room_encrypted(room: &room::Joined) -> Result<bool, String> {
+    let request = get_state_events_for_key::v3::Request::new(
+        room.room_id(),
+        StateEventType::RoomEncryption,
+        "",
+    );
+    match room.client().send(request, None).await {
+        Ok(_) => Ok(true),
+        Err(HttpError::Api(FromHttpResponseError::Server(ServerError::Known(
+            RumaApiError::ClientApi(error),
+        )))) if error.kind == ErrorKind::NotFound => Ok(false),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+fn is_authorized(request: &Request<Body>, hs_token: &str) -> bool {
+    let query_token = request.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == "access_token")
+            .map(|(_, value)| percent_decode(value))
+    });
+    let header_token = request
+        .headers()
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+    query_token.or(header_token).as_deref() == Some(hs_token)
+}
+
+async fn handle_request(
+    client: Client,
+    bot_configuration: Configuration,
+    hs_token: String,
+    request: Request<Body>,
+) -> Response<Body> {
+    if !is_authorized(&request, &hs_token) {
+        return json_response(
+            StatusCode::FORBIDDEN,
+            serde_json::json!({ "errcode": "M_FORBIDDEN" }),
+        );
+    }
+
+    let path = request.uri().path().to_string();
+    let path = path.strip_prefix("/_matrix/app/v1").unwrap_or(&path);
+    let segments: Vec<String> = path
+        .trim_start_matches('/')
+        .split('/')
+        .map(percent_decode)
+        .collect();
+    match (request.method(), segments.as_slice()) {
+        (&Method::PUT, [route, transaction_id]) if route == "transactions" => {
+            let transaction_id = transaction_id.to_string();
+            let body = match hyper::body::to_bytes(request.into_body()).await {
+                Ok(body) => body,
+                Err(error) => {
+                    log_error(error);
+                    return json_response(StatusCode::BAD_REQUEST, serde_json::json!({}));
+                }
+            };
+            let transaction = match serde_json::from_slice::<Transaction>(&body) {
+                Ok(transaction) => transaction,
+                Err(error) => {
+                    log_error(&error);
+                    return json_response(
+                        StatusCode::BAD_REQUEST,
+                        serde_json::json!({ "errcode": "M_NOT_JSON", "error": error.to_string() }),
+                    );
+                }
+            };
+            let sync_response = transaction_to_sync_response(
+                client.user_id().unwrap(),
+                &transaction_id,
+                transaction.events,
+            );
+            match client
+                .receive_transaction(
+                    <&TransactionId>::from(transaction_id.as_str()),
+                    sync_response,
+                )
+                .await
+            {
+                Ok(_) => json_response(StatusCode::OK, serde_json::json!({})),
+                Err(error) => {
+                    log_error(&error);
+                    json_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        serde_json::json!({ "errcode": "M_UNKNOWN", "error": error.to_string() }),
+                    )
+                }
+            }
+        }
+        (&Method::GET, [route, user_id]) if route == "users" => match UserId::parse(user_id) {
+            Ok(user_id) if is_ghost(&bot_configuration, &user_id) => {
+                json_response(StatusCode::OK, serde_json::json!({}))
+            }
+            _ => json_response(
+                StatusCode::NOT_FOUND,
+                serde_json::json!({ "errcode": "M_NOT_FOUND" }),
+            ),
+        },
+        _ => json_response(
+            StatusCode::NOT_FOUND,
+            serde_json::json!({ "errcode": "M_NOT_FOUND" }),
+        ),
+    }
+}
+
+/// Listens for transactions from the homeserver, taking the place
+/// of the sync loop when running as an application service.
+pub async fn serve_transactions(
+    client: Client,
+    bot_configuration: Configuration,
+    hs_token: String,
+) -> Result<(), String> {
+    let address: SocketAddr = match bot_configuration.appservice_listen_address.parse() {
+        Ok(address) => address,
+        Err(error) => return Err(format!("invalid listen address: {error}")),
+    };
+    let make_service = make_service_fn(move |_| {
+        let client = client.clone();
+        let bot_configuration = bot_configuration.clone();
+        let hs_token = hs_token.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let client = client.clone();
+                let bot_configuration = bot_configuration.clone();
+                let hs_token = hs_token.clone();
+                async move {
+                    Ok::<_, Infallible>(
+                        handle_request(client, bot_configuration, hs_token, request).await,
+                    )
+                }
+            }))
+        }
+    });
+    let server = match Server::try_bind(&address) {
+        Ok(server) => server.serve(make_service),
+        Err(error) => return Err(format!("unable to listen on {address}: {error}")),
+    };
+    match server.await {
+        Ok(_) => Ok(()),
+        Err(error) => Err(format!("server error: {error}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_namespace_regexes() {
+        assert_eq!(escape_regex("netchat_"), "netchat_");
+        assert_eq!(escape_regex("nc.bridge-"), "nc\\.bridge\\-");
+    }
+
+    #[test]
+    fn refuses_empty_ghost_prefixes() {
+        let bot_configuration = Configuration {
+            appservice_mode: true,
+            ghost_user_prefix: String::new(),
+            ..Configuration::default()
+        };
+        assert!(generate_registration(&bot_configuration, "bridge").is_err());
+        assert!(!is_ghost(
+            &bot_configuration,
+            &UserId::parse("@alice:example.org").unwrap()
+        ));
+        assert!(generate_registration(&Configuration::default(), "bridge").is_ok());
+    }
+
+    #[test]
+    fn escapes_ghost_localparts() {
+        assert_eq!(ghost_localpart("netchat_", "alice"), "netchat_alice");
+        assert_eq!(ghost_localpart("netchat_", "Bob_2"), "netchat__bob__2");
+        assert_eq!(ghost_localpart("netchat_", "a b!"), "netchat_a=20b=21");
+        assert_eq!(ghost_localpart("netchat_", "é"), "netchat_=c3=a9");
+    }
+}
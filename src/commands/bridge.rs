@@ -1,268 +1,599 @@
 use super::CommandInput;
 use crate::{
-    language::get_text, logging::log_error, netchat, permissions::Action, utilities,
-    BridgedRoomData,
+    appservice,
+    language::get_text,
+    logging::log_error,
+    netchat::{self, ExportFormat, NetChatMessage},
+    permissions::Action,
+    utilities, BridgedRoomData,
 };
+use clap::ValueEnum;
+use matrix_sdk::{
+    attachment::AttachmentConfig,
+    event_handler::Ctx,
+    ruma::{events::room::member::MembershipState, RoomId},
+};
+
+/// The history is sent as a single event, which homeservers limit to 64 KiB.
+const MAX_BACKFILL_MESSAGES: usize = 100;
 
 pub async fn bridge_command(command_input: &CommandInput) {
-    if command_input.arguments.len() < 1 {
+    if command_input.arguments.is_empty() {
         utilities::send_html_message(
             &command_input.room,
             get_text("missing_subcommand")
-                .replace("{subcommands}", "create/destroy/status")
+                .replace(
+                    "{subcommands}",
+                    "create/destroy/status/notices/redactions/interval/export",
+                )
                 .as_str(),
         )
         .await;
         return;
     }
-    if command_input.arguments[0] == "create" {
-        if command_input.arguments.len() < 3 {
-            utilities::send_html_message(
-                &command_input.room,
-                get_text("missing_arguments")
-                    .replace("{count}", "2")
-                    .replace("{arguments}", "create [room_name] [room_password]")
-                    .as_str(),
-            )
-            .await;
-            return;
-        }
+    if command_input.arguments[0] == "create" && command_input.arguments.len() < 3 {
+        utilities::send_html_message(
+            &command_input.room,
+            get_text("missing_arguments")
+                .replace("{count}", "2")
+                .replace(
+                    "{arguments}",
+                    "create [room_name] [room_password] (instance_url) (--backfill count) (--target room_id)",
+                )
+                .as_str(),
+        )
+        .await;
+        return;
+    }
+    if (command_input.arguments[0] == "notices" || command_input.arguments[0] == "redactions")
+        && !matches!(
+            command_input.arguments.get(1).map(String::as_str),
+            Some("on") | Some("off")
+        )
+    {
+        utilities::send_html_message(
+            &command_input.room,
+            get_text("missing_arguments")
+                .replace("{count}", "1")
+                .replace(
+                    "{arguments}",
+                    &format!("{} [on/off]", command_input.arguments[0]),
+                )
+                .as_str(),
+        )
+        .await;
+        return;
+    }
+    if command_input.arguments[0] == "interval"
+        && !command_input.arguments.get(1).is_some_and(|argument| {
+            argument == "auto" || argument.parse::<u64>().is_ok_and(|seconds| seconds > 0)
+        })
+    {
+        utilities::send_html_message(
+            &command_input.room,
+            get_text("missing_arguments")
+                .replace("{count}", "1")
+                .replace("{arguments}", "interval [seconds/auto]")
+                .as_str(),
+        )
+        .await;
+        return;
+    }
+    if command_input.arguments[0] == "export"
+        && command_input
+            .arguments
+            .get(1)
+            .is_some_and(|argument| ExportFormat::from_str(argument, true).is_err())
+    {
+        utilities::send_html_message(
+            &command_input.room,
+            get_text("missing_arguments")
+                .replace("{count}", "0")
+                .replace("{arguments}", "export (jsonl/text)")
+                .as_str(),
+        )
+        .await;
+        return;
     }
 
     match command_input.arguments[0].as_str() {
         "create" => {
-            if utilities::handle_permissions(command_input, Action::BridgeCreate).await {
-                return;
-            };
-
-            let room_name = &command_input.arguments[1];
-            let room_password = &command_input.arguments[2];
-            match command_input
-                .matrix_context
-                .database
-                .get(&format!("bridge.{}", command_input.room.room_id().as_str()))
+            // the room password shouldn't stay in the history of the room (or
+            // of the direct message) that the command was sent in, so the
+            // command is deleted before talking to NetChat
+            if command_input
+                .room
+                .redact(&command_input.event.event_id, None, None)
+                .await
+                .is_err()
             {
-                Ok(value) => match value {
-                    Some(_value) => {
-                        utilities::send_plain_message(
+                utilities::send_plain_message(
+                    &command_input.room,
+                    get_text("command_not_redacted"),
+                )
+                .await;
+            }
+            match command_input.options.get("target") {
+                Some(target) => {
+                    if let Some(target_input) =
+                        get_target_command_input(command_input, target).await
+                    {
+                        create_bridge(&target_input).await;
+                        utilities::send_html_message(
                             &command_input.room,
-                            get_text("room_already_bridged"),
+                            &get_text("target_room_replied")
+                                .replace("{room_id}", target_input.room.room_id().as_str()),
                         )
                         .await;
-                        return;
                     }
-                    None => (),
-                },
-                Err(error) => {
-                    log_error(&error);
-                    utilities::send_html_message(
-                        &command_input.room,
-                        get_text("database_error")
-                            .replace("{error}", &error)
-                            .as_str(),
-                    )
-                    .await;
-                    return;
                 }
+                None => create_bridge(command_input).await,
             }
-
-            utilities::set_typing(&command_input.room, true).await;
-            match netchat::get_room(
-                &command_input.matrix_context.bot_configuration,
-                room_name,
-                room_password,
-            )
-            .await
-            {
-                Ok(_) => (),
-                Err(error) => {
-                    log_error(&error);
-                    utilities::send_html_message(
-                        &command_input.room,
-                        &get_text("fetch_room_failed").replace("{error}", &error.to_string()),
-                    )
-                    .await;
-                    return;
-                }
-            };
-            let message_count = match netchat::get_room_message_count(
-                &command_input.matrix_context.bot_configuration,
-                room_name,
-                room_password,
-            )
-            .await
-            {
-                Ok(message_count) => message_count,
-                Err(error) => {
-                    log_error(&error);
-                    utilities::send_html_message(
-                        &command_input.room,
-                        &get_text("fetch_room_failed").replace("{error}", &error.to_string()),
-                    )
-                    .await;
-                    return;
-                }
+        }
+        "destroy" => {
+            if utilities::handle_permissions(command_input, Action::BridgeDestroy).await {
+                return;
             };
 
-            match command_input.matrix_context.database.set(
-                &format!("bridge.{}", command_input.room.room_id().as_str()),
-                serde_json::to_string(&BridgedRoomData {
-                    room_name: room_name.to_string(),
-                    room_password: room_password.to_string(),
-                    message_count,
-                    pending_messages: 0,
-                })
-                .unwrap()
-                .as_str(),
-            ) {
-                Ok(_) => (),
-                Err(error) => {
-                    log_error(&error);
-                    utilities::send_html_message(
-                        &command_input.room,
-                        get_text("database_error")
-                            .replace("{error}", &error)
-                            .as_str(),
-                    )
-                    .await;
-                    return;
-                }
+            let room_name = match get_bridged_room_data(command_input).await {
+                Some(bridged_room_data) => bridged_room_data.room_name,
+                None => return,
             };
+
+            if let Err(error) = command_input
+                .matrix_context
+                .bridges
+                .remove(command_input.room.room_id().as_str())
+            {
+                log_error(&error);
+                utilities::send_store_error(&command_input.room, &error).await;
+                return;
+            }
+            let matrix_room_id = command_input.room.room_id().as_str();
+            if let Err(error) = command_input
+                .matrix_context
+                .relayed
+                .remove_room(matrix_room_id)
+            {
+                log_error(&error);
+            }
+            if let Err(error) = command_input
+                .matrix_context
+                .outbound
+                .remove_room(matrix_room_id)
+            {
+                log_error(&error);
+            }
             utilities::send_html_message(
                 &command_input.room,
-                get_text("room_successfully_bridged")
+                get_text("room_successfully_unbridged")
                     .replace("{room_name}", &room_name)
                     .as_str(),
             )
             .await;
         }
-        "destroy" => {
-            if utilities::handle_permissions(command_input, Action::BridgeDestroy).await {
+        "notices" | "redactions" | "interval" => {
+            if utilities::handle_permissions(command_input, Action::BridgeConfigure).await {
                 return;
             };
 
-            let room_name = match command_input
-                .matrix_context
-                .database
-                .get(&format!("bridge.{}", command_input.room.room_id().as_str()))
-            {
-                Ok(value) => match value {
-                    Some(value) => match serde_json::from_str::<BridgedRoomData>(value.as_str()) {
-                        Ok(bridged_room_data) => bridged_room_data.room_name,
-                        Err(error) => {
-                            log_error(&error);
-                            utilities::send_html_message(
-                                &command_input.room,
-                                get_text("database_possibly_corrupted")
-                                    .replace("{error}", &error.to_string())
-                                    .as_str(),
-                            )
-                            .await;
-                            return;
-                        }
-                    },
-                    None => {
-                        utilities::send_plain_message(
-                            &command_input.room,
-                            get_text("room_not_bridged"),
-                        )
-                        .await;
-                        return;
-                    }
+            let enabled = command_input.arguments[1] == "on";
+            let bridged_room_data = match command_input.matrix_context.bridges.update(
+                command_input.room.room_id().as_str(),
+                |bridged_room_data| match command_input.arguments[0].as_str() {
+                    "notices" => bridged_room_data.relay_notices = enabled,
+                    "redactions" => bridged_room_data.relay_redactions = enabled,
+                    _ => bridged_room_data.poll_interval = command_input.arguments[1].parse().ok(),
                 },
-                Err(error) => {
-                    log_error(&error);
-                    utilities::send_html_message(
+            ) {
+                Ok(Some(bridged_room_data)) => bridged_room_data,
+                Ok(None) => {
+                    utilities::send_plain_message(
                         &command_input.room,
-                        get_text("database_error")
-                            .replace("{error}", &error)
-                            .as_str(),
+                        get_text("room_not_bridged"),
                     )
                     .await;
                     return;
                 }
-            };
-
-            match command_input
-                .matrix_context
-                .database
-                .remove(&format!("bridge.{}", command_input.room.room_id().as_str()))
-            {
-                Ok(_) => (),
                 Err(error) => {
                     log_error(&error);
-                    utilities::send_html_message(
-                        &command_input.room,
-                        get_text("database_error")
-                            .replace("{error}", &error)
-                            .as_str(),
-                    )
-                    .await;
+                    utilities::send_store_error(&command_input.room, &error).await;
                     return;
                 }
             };
-            utilities::send_html_message(
+            if command_input.arguments[0] == "interval" {
+                utilities::send_html_message(
+                    &command_input.room,
+                    &match bridged_room_data.poll_interval {
+                        Some(seconds) => {
+                            get_text("interval_pinned").replace("{seconds}", &seconds.to_string())
+                        }
+                        None => get_text("interval_adaptive").to_string(),
+                    },
+                )
+                .await;
+                return;
+            }
+            utilities::send_plain_message(
                 &command_input.room,
-                get_text("room_successfully_unbridged")
-                    .replace("{room_name}", &room_name)
-                    .as_str(),
+                get_text(&format!(
+                    "{}_{}",
+                    command_input.arguments[0],
+                    if enabled { "enabled" } else { "disabled" }
+                )),
             )
             .await;
         }
         "status" | "info" | "information" => {
-            let bridged_room_data = match command_input
+            let bridged_room_data = match get_bridged_room_data(command_input).await {
+                Some(bridged_room_data) => bridged_room_data,
+                None => return,
+            };
+            let instance_url =
+                bridged_room_data.instance_url(&command_input.matrix_context.bot_configuration);
+            let mut room_status = get_text("room_status")
+                .replace("{room_name}", &bridged_room_data.room_name)
+                .replace("{instance_url}", instance_url)
+                .replace(
+                    "{room_message_count}",
+                    &bridged_room_data.message_count.to_string(),
+                );
+            room_status.push(' ');
+            room_status.push_str(
+                &get_text(if bridged_room_data.poll_interval.is_some() {
+                    "room_interval_pinned"
+                } else {
+                    "room_interval_adaptive"
+                })
+                .replace(
+                    "{seconds}",
+                    &bridged_room_data
+                        .poll_interval(&command_input.matrix_context.bot_configuration)
+                        .as_secs()
+                        .to_string(),
+                ),
+            );
+            if let Some(backoff_state) = command_input
                 .matrix_context
-                .database
-                .get(&format!("bridge.{}", command_input.room.room_id().as_str()))
+                .netchat_client
+                .with_instance_url(instance_url)
+                .backoff_state(&bridged_room_data.room_name)
             {
-                Ok(value) => match value {
-                    Some(value) => match serde_json::from_str::<BridgedRoomData>(value.as_str()) {
-                        Ok(bridged_room_data) => bridged_room_data,
-                        Err(error) => {
-                            log_error(&error);
-                            utilities::send_html_message(
-                                &command_input.room,
-                                get_text("database_possibly_corrupted")
-                                    .replace("{error}", &error.to_string())
-                                    .as_str(),
-                            )
-                            .await;
-                            return;
-                        }
-                    },
-                    None => {
-                        utilities::send_plain_message(
-                            &command_input.room,
-                            get_text("room_not_bridged"),
-                        )
-                        .await;
-                        return;
-                    }
-                },
+                room_status.push(' ');
+                room_status.push_str(
+                    &get_text("room_backing_off")
+                        .replace("{seconds}", &backoff_state.remaining.as_secs().to_string())
+                        .replace("{failures}", &backoff_state.failures.to_string()),
+                );
+            }
+            utilities::send_html_message(&command_input.room, &room_status).await;
+        }
+        "export" => {
+            if utilities::handle_permissions(command_input, Action::BridgeExport).await {
+                return;
+            };
+
+            let bridged_room_data = match get_bridged_room_data(command_input).await {
+                Some(bridged_room_data) => bridged_room_data,
+                None => return,
+            };
+            let format = match command_input.arguments.get(1) {
+                Some(argument) => ExportFormat::from_str(argument, true).unwrap(),
+                None => ExportFormat::Jsonl,
+            };
+
+            utilities::set_typing(&command_input.room, true).await;
+            let raw_messages = match command_input
+                .matrix_context
+                .netchat_client
+                .with_instance_url(
+                    bridged_room_data.instance_url(&command_input.matrix_context.bot_configuration),
+                )
+                .get_room_messages(
+                    &bridged_room_data.room_name,
+                    &bridged_room_data.room_password,
+                )
+                .await
+            {
+                Ok(raw_messages) => raw_messages,
                 Err(error) => {
                     log_error(&error);
+                    utilities::set_typing(&command_input.room, false).await;
                     utilities::send_html_message(
                         &command_input.room,
-                        get_text("database_error")
-                            .replace("{error}", &error)
-                            .as_str(),
+                        &get_text("fetch_room_failed").replace("{error}", &error.to_string()),
                     )
                     .await;
                     return;
                 }
             };
+            let file_name = format!(
+                "{}-{}.{}",
+                bridged_room_data.room_name,
+                chrono::Utc::now().format("%Y%m%d-%H%M%S"),
+                format.extension()
+            );
+            let result = command_input
+                .room
+                .send_attachment(
+                    &file_name,
+                    &format.content_type().parse().unwrap(),
+                    netchat::export_messages(&raw_messages, format).as_bytes(),
+                    AttachmentConfig::new(),
+                )
+                .await;
+            utilities::set_typing(&command_input.room, false).await;
+            if let Err(error) = result {
+                log_error(&error);
+                utilities::send_html_message(
+                    &command_input.room,
+                    &get_text("room_export_failed").replace("{error}", &error.to_string()),
+                )
+                .await;
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Fetches the bridge of the room the command was sent in,
+/// telling the user if there is none (or it can't be read).
+async fn get_bridged_room_data(command_input: &CommandInput) -> Option<BridgedRoomData> {
+    match command_input
+        .matrix_context
+        .bridges
+        .get(command_input.room.room_id().as_str())
+    {
+        Ok(Some(bridged_room_data)) => Some(bridged_room_data),
+        Ok(None) => {
+            utilities::send_plain_message(&command_input.room, get_text("room_not_bridged")).await;
+            None
+        }
+        Err(error) => {
+            log_error(&error);
+            utilities::send_store_error(&command_input.room, &error).await;
+            None
+        }
+    }
+}
+
+async fn create_bridge(command_input: &CommandInput) {
+    if utilities::handle_permissions(command_input, Action::BridgeCreate).await {
+        return;
+    };
+
+    let room_name = &command_input.arguments[1];
+    let room_password = &command_input.arguments[2];
+    let instance_url = command_input
+        .arguments
+        .get(3)
+        .map(|instance_url| instance_url.trim_end_matches('/').to_string());
+    let backfill_count = match command_input.options.get("backfill") {
+        Some(count) => match count.parse::<usize>() {
+            Ok(count) if count <= MAX_BACKFILL_MESSAGES => count,
+            _ => {
+                utilities::send_html_message(
+                    &command_input.room,
+                    get_text("invalid_option")
+                        .replace("{option}", "backfill")
+                        .replace(
+                            "{expected}",
+                            &format!("a number of messages (at most {MAX_BACKFILL_MESSAGES})"),
+                        )
+                        .as_str(),
+                )
+                .await;
+                return;
+            }
+        },
+        None => 0,
+    };
+    let bridge_instance_url = match &instance_url {
+        Some(instance_url) => instance_url.as_str(),
+        None => command_input
+            .matrix_context
+            .bot_configuration
+            .instance_url
+            .trim_end_matches('/'),
+    };
+    match command_input
+        .matrix_context
+        .bridges
+        .get(command_input.room.room_id().as_str())
+    {
+        Ok(Some(_)) => {
+            utilities::send_plain_message(&command_input.room, get_text("room_already_bridged"))
+                .await;
+            return;
+        }
+        Ok(None) => (),
+        Err(error) => {
+            log_error(&error);
+            utilities::send_store_error(&command_input.room, &error).await;
+            return;
+        }
+    }
+
+    let is_encrypted = match command_input.matrix_context.ghost_users {
+        Some(_) => match appservice::is_room_encrypted(&command_input.room).await {
+            Ok(is_encrypted) => is_encrypted,
+            Err(error) => {
+                log_error(&error);
+                utilities::send_html_message(
+                    &command_input.room,
+                    &get_text("encryption_check_failed").replace("{error}", &error),
+                )
+                .await;
+                return;
+            }
+        },
+        None => command_input.room.is_encrypted(),
+    };
+    if is_encrypted
+        && !command_input
+            .matrix_context
+            .bot_configuration
+            .encryption_enabled()
+    {
+        utilities::send_plain_message(&command_input.room, get_text("room_encrypted")).await;
+        return;
+    }
+
+    utilities::set_typing(&command_input.room, true).await;
+    let netchat_client = command_input
+        .matrix_context
+        .netchat_client
+        .with_instance_url(bridge_instance_url);
+    match netchat_client.get_room(room_name, room_password).await {
+        Ok(_) => (),
+        Err(error) => {
+            log_error(&error);
             utilities::send_html_message(
                 &command_input.room,
-                get_text("room_status")
-                    .replace("{room_name}", &bridged_room_data.room_name)
-                    .replace(
-                        "{room_message_count}",
-                        &bridged_room_data.message_count.to_string(),
-                    )
-                    .as_str(),
+                &get_text("fetch_room_failed").replace("{error}", &error.to_string()),
+            )
+            .await;
+            return;
+        }
+    };
+    let message_count = match netchat_client
+        .get_room_message_count(room_name, room_password)
+        .await
+    {
+        Ok(message_count) => message_count,
+        Err(error) => {
+            log_error(&error);
+            utilities::send_html_message(
+                &command_input.room,
+                &get_text("fetch_room_failed").replace("{error}", &error.to_string()),
             )
             .await;
+            return;
+        }
+    };
+    let history = if backfill_count > 0 {
+        match netchat_client
+            .get_room_messages(room_name, room_password)
+            .await
+        {
+            Ok(mut room_messages) => {
+                // anything newer than the stored count is bridged by the next poll
+                room_messages.truncate(message_count);
+                room_messages.split_off(room_messages.len().saturating_sub(backfill_count))
+            }
+            Err(error) => {
+                log_error(&error);
+                utilities::send_html_message(
+                    &command_input.room,
+                    &get_text("fetch_room_failed").replace("{error}", &error.to_string()),
+                )
+                .await;
+                return;
+            }
+        }
+    } else {
+        Vec::new()
+    };
+    // The history is posted as one collapsed message before the bridge is
+    // stored, so that newer messages from the poll loop can't end up in it.
+    if !history.is_empty() {
+        let (plain, html) = format_history(room_name, &history);
+        utilities::send_notice(&command_input.room, &plain, Some(&html)).await;
+    }
+
+    if let Err(error) = command_input.matrix_context.bridges.set(
+        command_input.room.room_id().as_str(),
+        &BridgedRoomData {
+            room_name: room_name.to_string(),
+            room_password: room_password.to_string(),
+            message_count,
+            instance_url,
+            sent_messages: Vec::new(),
+            relay_notices: false,
+            relay_redactions: false,
+            poll_interval: None,
+            last_activity: chrono::Utc::now().timestamp(),
+        },
+    ) {
+        log_error(&error);
+        utilities::send_store_error(&command_input.room, &error).await;
+        return;
+    }
+    utilities::send_html_message(
+        &command_input.room,
+        get_text("room_successfully_bridged")
+            .replace("{room_name}", room_name)
+            .as_str(),
+    )
+    .await;
+}
+
+/// The plain and HTML bodies of the backfill notice, which keeps
+/// the messages collapsed under its header.
+fn format_history(room_name: &str, history: &[String]) -> (String, String) {
+    let messages: Vec<NetChatMessage> = history
+        .iter()
+        .map(|raw_message| NetChatMessage::parse(raw_message))
+        .collect();
+    let count = messages.len().to_string();
+    let mut plain = get_text("room_history_plain")
+        .replace("{count}", &count)
+        .replace("{room_name}", room_name);
+    for message in &messages {
+        plain.push('\n');
+        plain.push_str(&message.to_plain());
+    }
+    let html = format!(
+        "<details><summary>{}</summary>{}</details>",
+        get_text("room_history")
+            .replace("{count}", &count)
+            .replace("{room_name}", &utilities::escape_html(room_name)),
+        messages
+            .iter()
+            .map(|message| {
+                message
+                    .to_html()
+                    .unwrap_or_else(|| utilities::escape_html(&message.to_plain()))
+            })
+            .collect::<Vec<_>>()
+            .join("<br>")
+    );
+    (plain, html)
+}
+
+/// Makes it possible to set up a bridge from a direct message with the bot,
+/// so that the room password isn't shown to everyone in the bridged room.
+/// The sender has to be in the target room, where their permissions are checked.
+async fn get_target_command_input(
+    command_input: &CommandInput,
+    target: &str,
+) -> Option<CommandInput> {
+    let room = RoomId::parse(target)
+        .ok()
+        .and_then(|room_id| command_input.room.client().get_joined_room(&room_id));
+    let is_member = match &room {
+        Some(room) => matches!(
+            room.get_member(&command_input.event.sender).await,
+            Ok(Some(member)) if *member.membership() == MembershipState::Join
+        ),
+        None => false,
+    };
+    match room {
+        Some(room) if is_member => Some(CommandInput {
+            event: command_input.event.clone(),
+            room,
+            matrix_context: Ctx(command_input.matrix_context.0.clone()),
+            arguments: command_input.arguments.clone(),
+            options: command_input.options.clone(),
+        }),
+        _ => {
+            utilities::send_html_message(
+                &command_input.room,
+                &get_text("target_room_unavailable")
+                    .replace("{room_id}", &utilities::escape_html(target)),
+            )
+            .await;
+            None
         }
-        _ => (),
     }
 }
@@ -5,10 +5,73 @@ pub mod username;
 use crate::MatrixContext;
 use matrix_sdk::room::Joined;
 use matrix_sdk::{event_handler::Ctx, ruma::events::room::message::OriginalSyncRoomMessageEvent};
+use std::collections::HashMap;
 
 pub struct CommandInput {
     pub event: OriginalSyncRoomMessageEvent,
     pub room: Joined,
     pub matrix_context: Ctx<MatrixContext>,
     pub arguments: Vec<String>,
+    pub options: HashMap<String, String>,
+}
+
+/// The `--name value` options a command takes, depending on its subcommand.
+fn known_options(command: &str, arguments: &[String]) -> &'static [&'static str] {
+    match (command, arguments.first().map(String::as_str)) {
+        ("bridge", Some("create")) => &["backfill", "target"],
+        _ => &[],
+    }
+}
+
+/// Separates the options that `command` takes from the positional arguments.
+/// Anything else starting with `--` (like a room password) is left as it is.
+pub fn split_options(
+    command: &str,
+    arguments: Vec<String>,
+) -> (Vec<String>, HashMap<String, String>) {
+    let known_options = known_options(command, &arguments);
+    let mut positional_arguments = Vec::new();
+    let mut options = HashMap::new();
+    let mut arguments = arguments.into_iter();
+    while let Some(argument) = arguments.next() {
+        match argument.strip_prefix("--") {
+            Some(name) if known_options.contains(&name) => {
+                options.insert(name.to_string(), arguments.next().unwrap_or_default());
+            }
+            _ => positional_arguments.push(argument),
+        }
+    }
+    (positional_arguments, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_options_from_arguments() {
+        let (arguments, options) = split_options(
+            "bridge",
+            ["create", "room", "--backfill", "50", "password", "--target"]
+                .map(String::from)
+                .to_vec(),
+        );
+        assert_eq!(arguments, ["create", "room", "password"]);
+        assert_eq!(options["backfill"], "50");
+        assert_eq!(options["target"], "");
+
+        let (arguments, options) = split_options(
+            "bridge",
+            ["create", "room", "--secret"].map(String::from).to_vec(),
+        );
+        assert_eq!(arguments, ["create", "room", "--secret"]);
+        assert!(options.is_empty());
+
+        let (arguments, options) = split_options(
+            "username",
+            ["set", "--backfill", "50"].map(String::from).to_vec(),
+        );
+        assert_eq!(arguments, ["set", "--backfill", "50"]);
+        assert!(options.is_empty());
+    }
 }
@@ -2,7 +2,7 @@ use super::CommandInput;
 use crate::{language::get_text, logging::log_error, utilities};
 
 pub async fn username_command(command_input: &CommandInput) {
-    if command_input.arguments.len() < 1 {
+    if command_input.arguments.is_empty() {
         utilities::send_html_message(
             &command_input.room,
             get_text("missing_subcommand")
@@ -12,40 +12,29 @@ pub async fn username_command(command_input: &CommandInput) {
         .await;
         return;
     }
-    if command_input.arguments[0] == "set" {
-        if command_input.arguments.len() < 2 {
-            utilities::send_html_message(
-                &command_input.room,
-                get_text("missing_arguments")
-                    .replace("{count}", "1")
-                    .replace("{arguments}", "set [name]")
-                    .as_str(),
-            )
-            .await;
-            return;
-        }
+    if command_input.arguments[0] == "set" && command_input.arguments.len() < 2 {
+        utilities::send_html_message(
+            &command_input.room,
+            get_text("missing_arguments")
+                .replace("{count}", "1")
+                .replace("{arguments}", "set [name]")
+                .as_str(),
+        )
+        .await;
+        return;
     }
 
     match command_input.arguments[0].as_str() {
         "set" => {
-            match command_input.matrix_context.database.set(
-                &format!(
-                    "username.{}.{}",
-                    command_input.room.room_id().as_str(),
-                    command_input.event.sender.as_str()
-                ),
+            match command_input.matrix_context.usernames.set(
+                command_input.room.room_id().as_str(),
+                command_input.event.sender.as_str(),
                 command_input.arguments[1].as_str(),
             ) {
                 Ok(_) => (),
                 Err(error) => {
                     log_error(&error);
-                    utilities::send_html_message(
-                        &command_input.room,
-                        get_text("database_error")
-                            .replace("{error}", &error)
-                            .as_str(),
-                    )
-                    .await;
+                    utilities::send_store_error(&command_input.room, &error).await;
                     return;
                 }
             }
@@ -58,11 +47,10 @@ pub async fn username_command(command_input: &CommandInput) {
             .await;
         }
         "get" => {
-            let username = match command_input.matrix_context.database.get(&format!(
-                "username.{}.{}",
+            let username = match command_input.matrix_context.usernames.get(
                 command_input.room.room_id().as_str(),
-                command_input.event.sender.as_str()
-            )) {
+                command_input.event.sender.as_str(),
+            ) {
                 Ok(username) => match username {
                     Some(username) => username,
                     None => {
@@ -76,13 +64,7 @@ pub async fn username_command(command_input: &CommandInput) {
                 },
                 Err(error) => {
                     log_error(&error);
-                    utilities::send_html_message(
-                        &command_input.room,
-                        get_text("database_error")
-                            .replace("{error}", &error)
-                            .as_str(),
-                    )
-                    .await;
+                    utilities::send_store_error(&command_input.room, &error).await;
                     return;
                 }
             };
@@ -95,21 +77,14 @@ pub async fn username_command(command_input: &CommandInput) {
             .await;
         }
         "clear" => {
-            match command_input.matrix_context.database.remove(&format!(
-                "username.{}.{}",
+            match command_input.matrix_context.usernames.remove(
                 command_input.room.room_id().as_str(),
-                command_input.event.sender.as_str()
-            )) {
+                command_input.event.sender.as_str(),
+            ) {
                 Ok(_) => (),
                 Err(error) => {
                     log_error(&error);
-                    utilities::send_html_message(
-                        &command_input.room,
-                        get_text("database_error")
-                            .replace("{error}", &error)
-                            .as_str(),
-                    )
-                    .await;
+                    utilities::send_store_error(&command_input.room, &error).await;
                     return;
                 }
             }
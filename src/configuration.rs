@@ -1,14 +1,23 @@
 use serde::{Deserialize, Serialize};
 use std::path::Path;
 
+/// Keys missing from the file take their value from `Configuration::default()`,
+/// so that older configuration files keep working when new settings are added.
 #[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Configuration {
-    #[serde(default)]
     pub command_prefix: String,
-    #[serde(default)]
     pub refresh_interval: u64,
-    #[serde(default)]
+    pub max_refresh_interval: u64,
     pub request_timeout: u64,
+    pub max_concurrent_polls: usize,
+    pub instance_url: String,
+    pub appservice_mode: bool,
+    pub ghost_user_prefix: String,
+    pub appservice_url: String,
+    pub appservice_listen_address: String,
+    pub enable_encryption: bool,
+    pub outbound_max_attempts: u32,
 }
 
 impl Default for Configuration {
@@ -16,26 +25,50 @@ impl Default for Configuration {
         Self {
             command_prefix: "!".to_string(),
             refresh_interval: 5,
+            max_refresh_interval: 120,
             request_timeout: 10,
+            max_concurrent_polls: 4,
+            instance_url: "https://netchat.repl.co".to_string(),
+            appservice_mode: false,
+            ghost_user_prefix: "netchat_".to_string(),
+            appservice_url: "http://localhost:29331".to_string(),
+            appservice_listen_address: "127.0.0.1:29331".to_string(),
+            enable_encryption: true,
+            outbound_max_attempts: 8,
         }
     }
 }
 
 impl Configuration {
+    /// Encryption keys are only delivered through /sync, which
+    /// appservices don't use to receive events.
+    pub fn encryption_enabled(&self) -> bool {
+        self.enable_encryption && !self.appservice_mode
+    }
+
     pub fn from_json_file(path: &Path) -> Result<Self, String> {
         let file_contents = match std::fs::read_to_string(path) {
             Ok(file_contents) => file_contents,
             Err(error) => {
                 return match error.kind() {
-                    std::io::ErrorKind::NotFound => Err(format!("file not found")),
+                    std::io::ErrorKind::NotFound => Err("file not found".to_string()),
                     _ => Err(format!("unable to read file: {error}")),
                 }
             }
         };
-        match serde_json::from_str(&file_contents) {
-            Ok(configuration) => Ok(configuration),
-            Err(error) => Err(error.to_string()),
+        Self::from_json(&file_contents)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let mut configuration: Self = match serde_json::from_str(json) {
+            Ok(configuration) => configuration,
+            Err(error) => return Err(error.to_string()),
+        };
+        // a message always gets at least one attempt
+        if configuration.outbound_max_attempts == 0 {
+            configuration.outbound_max_attempts = Self::default().outbound_max_attempts;
         }
+        Ok(configuration)
     }
 
     pub fn to_json_file(&self, path: &Path) -> Result<(), String> {
@@ -48,3 +81,49 @@ impl Configuration {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_in_missing_keys_with_defaults() {
+        let configuration = Configuration::from_json(
+            r#"{"command_prefix": "?", "refresh_interval": 3, "request_timeout": 20}"#,
+        )
+        .unwrap();
+        let defaults = Configuration::default();
+        assert_eq!(configuration.command_prefix, "?");
+        assert_eq!(configuration.refresh_interval, 3);
+        assert_eq!(configuration.request_timeout, 20);
+        assert_eq!(configuration.instance_url, defaults.instance_url);
+        assert_eq!(
+            configuration.max_refresh_interval,
+            defaults.max_refresh_interval
+        );
+        assert_eq!(
+            configuration.max_concurrent_polls,
+            defaults.max_concurrent_polls
+        );
+        assert_eq!(configuration.ghost_user_prefix, defaults.ghost_user_prefix);
+        assert_eq!(configuration.appservice_url, defaults.appservice_url);
+        assert_eq!(
+            configuration.appservice_listen_address,
+            defaults.appservice_listen_address
+        );
+        assert_eq!(
+            configuration.outbound_max_attempts,
+            defaults.outbound_max_attempts
+        );
+        assert!(configuration.enable_encryption);
+    }
+
+    #[test]
+    fn treats_zero_attempts_as_default() {
+        let configuration = Configuration::from_json(r#"{"outbound_max_attempts": 0}"#).unwrap();
+        assert_eq!(
+            configuration.outbound_max_attempts,
+            Configuration::default().outbound_max_attempts
+        );
+    }
+}
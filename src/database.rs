@@ -14,6 +14,13 @@ impl Database {
         })
     }
 
+    #[cfg(test)]
+    pub fn temporary() -> Self {
+        Self {
+            database: sled::Config::new().temporary(true).open().unwrap(),
+        }
+    }
+
     pub fn set(&self, key: &str, value: &str) -> Result<(), String> {
         match self.database.insert(key, value) {
             Ok(_) => Ok(()),
@@ -38,9 +45,51 @@ impl Database {
         }
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (String, String)> {
+    /// Atomically replaces the value of `key` with the result of `update`,
+    /// which may be called several times if the value changes concurrently.
+    /// Returning `None` removes the key.
+    pub fn update_and_fetch(
+        &self,
+        key: &str,
+        mut update: impl FnMut(Option<&str>) -> Option<String>,
+    ) -> Result<Option<String>, String> {
+        match self.database.update_and_fetch(key, |value| {
+            update(value.map(|value| std::str::from_utf8(value).unwrap())).map(String::into_bytes)
+        }) {
+            Ok(value) => Ok(value.map(|value| std::str::from_utf8(&value).unwrap().to_string())),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    /// Writes all entries at once, or none of them if anything fails.
+    pub fn apply_batch(&self, entries: Vec<(String, String)>) -> Result<(), String> {
+        let mut batch = sled::Batch::default();
+        for (key, value) in entries {
+            batch.insert(key.as_str(), value.as_str());
+        }
+        match self.database.apply_batch(batch) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    pub fn generate_id(&self) -> Result<u64, String> {
+        match self.database.generate_id() {
+            Ok(id) => Ok(id),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    pub async fn flush(&self) -> Result<(), String> {
+        match self.database.flush_async().await {
+            Ok(_) => Ok(()),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    pub fn scan_prefix(&self, prefix: &str) -> impl Iterator<Item = (String, String)> {
         self.database
-            .iter()
+            .scan_prefix(prefix)
             .filter(|result| result.is_ok())
             .map(|result| {
                 (
@@ -0,0 +1,164 @@
+//! Encryption of sensitive values (like NetChat room passwords) before
+//! they are written to the database, so that a copy of the database
+//! alone doesn't give anyone access to the bridged rooms.
+
+use crate::secrets::Secrets;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, NewAead, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand::Rng;
+use std::io::Write;
+use std::path::Path;
+
+const ENCRYPTED_PREFIX: &str = "encrypted:";
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+
+#[derive(Clone)]
+pub struct DatabaseKey {
+    key: [u8; KEY_LENGTH],
+}
+
+impl DatabaseKey {
+    pub fn generate() -> Self {
+        let mut key = [0; KEY_LENGTH];
+        rand::thread_rng().fill(&mut key);
+        Self { key }
+    }
+
+    pub fn from_base64(encoded: &str) -> Result<Self, String> {
+        let decoded = match STANDARD.decode(encoded.trim()) {
+            Ok(decoded) => decoded,
+            Err(error) => return Err(format!("invalid database key: {error}")),
+        };
+        match decoded.try_into() {
+            Ok(key) => Ok(Self { key }),
+            Err(_) => Err(format!(
+                "invalid database key: it has to be {KEY_LENGTH} bytes long"
+            )),
+        }
+    }
+
+    pub fn to_base64(&self) -> String {
+        STANDARD.encode(self.key)
+    }
+
+    /// Uses the key from the secrets, or the one in the configured key
+    /// file (which is created with a new key if it doesn't exist yet).
+    /// Without either, values are stored unencrypted.
+    pub fn load(secrets: &Secrets) -> Result<Option<Self>, String> {
+        if let Some(database_key) = &secrets.database_key {
+            return Self::from_base64(database_key).map(Some);
+        }
+        let key_file = match &secrets.database_key_file {
+            Some(key_file) => Path::new(key_file),
+            None => return Ok(None),
+        };
+        match std::fs::read_to_string(key_file) {
+            Ok(encoded) => Self::from_base64(&encoded).map(Some),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                let key = Self::generate();
+                key.save(key_file)?;
+                Ok(Some(key))
+            }
+            Err(error) => Err(format!("unable to read {}: {error}", key_file.display())),
+        }
+    }
+
+    /// The file is only readable by its owner (on unix).
+    pub fn save(&self, key_file: &Path) -> Result<(), String> {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        match options
+            .open(key_file)
+            .and_then(|mut file| file.write_all(self.to_base64().as_bytes()))
+        {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!("unable to write {}: {error}", key_file.display())),
+        }
+    }
+
+    /// `context` (for example the room the value belongs to) has to
+    /// match when decrypting, so values can't be swapped around.
+    pub fn encrypt(&self, plaintext: &str, context: &str) -> String {
+        let mut nonce = [0; NONCE_LENGTH];
+        rand::thread_rng().fill(&mut nonce);
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&self.key))
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: context.as_bytes(),
+                },
+            )
+            .unwrap();
+        format!(
+            "{ENCRYPTED_PREFIX}{}",
+            STANDARD.encode([nonce.as_slice(), &ciphertext].concat())
+        )
+    }
+
+    pub fn decrypt(&self, value: &str, context: &str) -> Result<String, String> {
+        let encoded = match value.strip_prefix(ENCRYPTED_PREFIX) {
+            Some(encoded) => encoded,
+            None => return Ok(value.to_string()),
+        };
+        let decoded = match STANDARD.decode(encoded) {
+            Ok(decoded) if decoded.len() > NONCE_LENGTH => decoded,
+            _ => return Err("malformed encrypted value".to_string()),
+        };
+        let (nonce, ciphertext) = decoded.split_at(NONCE_LENGTH);
+        match ChaCha20Poly1305::new(Key::from_slice(&self.key)).decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: context.as_bytes(),
+            },
+        ) {
+            Ok(plaintext) => Ok(String::from_utf8_lossy(&plaintext).to_string()),
+            Err(_) => Err("unable to decrypt value (wrong database key?)".to_string()),
+        }
+    }
+}
+
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(ENCRYPTED_PREFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypts_and_decrypts_values() {
+        let key = DatabaseKey::generate();
+        let encrypted = key.encrypt("hunter2", "!a:example.org");
+        assert!(is_encrypted(&encrypted));
+        assert!(!encrypted.contains("hunter2"));
+        assert_eq!(
+            key.decrypt(&encrypted, "!a:example.org").unwrap(),
+            "hunter2"
+        );
+
+        assert!(key.decrypt(&encrypted, "!b:example.org").is_err());
+        assert!(DatabaseKey::generate()
+            .decrypt(&encrypted, "!a:example.org")
+            .is_err());
+        // values from before encryption was set up are passed through
+        assert_eq!(key.decrypt("hunter2", "!a:example.org").unwrap(), "hunter2");
+    }
+
+    #[test]
+    fn round_trips_keys() {
+        let key = DatabaseKey::generate();
+        assert_eq!(
+            DatabaseKey::from_base64(&key.to_base64()).unwrap().key,
+            key.key
+        );
+        assert!(DatabaseKey::from_base64("c2hvcnQ=").is_err());
+    }
+}
@@ -7,15 +7,34 @@ static TEXTS: phf::Map<&'static str, &'static str> = phf_map! {
     "fetch_permissions_failed" => "Uh oh! An error occurred while fetching your room permissions (<code>{error}</code>). For safety reasons, you have been denied access to use this command.",
     "missing_subcommand" => "This command requires a subcommand! Valid choices are <b>{subcommands}</b>.",
     "missing_arguments" => "You did not supply enough arguments! This command requires at least <b>{count} argument(s)</b> ({arguments}).",
+    "invalid_option" => "The value of <code>--{option}</code> has to be {expected}.",
     "database_error" => "Uh oh! Something went wrong while interacting with the database (<code>{error}</code>). Please try again later.",
     "database_possibly_corrupted" => "Uh oh! Something went wrong while processing data from the database (<code>{error}</code>). This issue might be resolved later.",
     "fetch_room_failed" => "Uh oh! An error occurred while fetching that NetChat room (<code>{error}</code>).",
+    "encryption_check_failed" => "Uh oh! An error occurred while checking whether this room is encrypted (<code>{error}</code>).",
+    "room_export_failed" => "Uh oh! An error occurred while uploading the history of this room (<code>{error}</code>).",
+    "target_room_unavailable" => "I can't set up a bridge in <code>{room_id}</code>, as either I or you haven't joined that room.",
+    "target_room_replied" => "Done! You can find my reply in <code>{room_id}</code>.",
+    "command_not_redacted" => "I wasn't able to delete your command, so the room password is still visible in this room's history. Please delete it yourself, or allow me to delete other people's messages.",
+    "room_history" => "📜 Here are the last <b>{count}</b> message(s) that were sent in <b>{room_name}</b> before it was bridged:",
+    "room_history_plain" => "📜 Here are the last {count} message(s) that were sent in {room_name} before it was bridged:",
     "room_already_bridged" => "Hmm, seems like this room has already been bridged. You can use the \"unbridge\" command to unbridge this room and try again.",
+    "room_encrypted" => "This Matrix room is end-to-end encrypted, but encryption support is disabled. Set \"enable_encryption\" in the configuration (appservice mode can't be used with encryption) and try again.",
     "room_not_bridged" => "This Matrix room is currently not bridged to any NetChat room.",
     "room_successfully_bridged" => "This Matrix room has been successfully bridged to <b>{room_name}</b>.",
     "room_successfully_unbridged" => "This Matrix room has been successfully unbridged from <b>{room_name}</b>.",
-    "room_status" => "This Matrix room is currently bridged to <b>{room_name}</b> (<b>{room_message_count}</b> messages).",
+    "room_status" => "This Matrix room is currently bridged to <b>{room_name}</b> on <code>{instance_url}</code> (<b>{room_message_count}</b> messages).",
+    "room_backing_off" => "Requests to NetChat are currently paused for <b>{seconds}s</b> after <b>{failures}</b> failed request(s) in a row.",
+    "room_interval_pinned" => "It is polled every <b>{seconds}s</b>.",
+    "room_interval_adaptive" => "It is currently polled every <b>{seconds}s</b>, depending on how active it is.",
+    "notices_enabled" => "Notices from this Matrix room (for example from other bots) will now be relayed to NetChat.",
+    "notices_disabled" => "Notices from this Matrix room will no longer be relayed to NetChat.",
+    "redactions_enabled" => "Deleted messages in this Matrix room will now be announced on NetChat.",
+    "redactions_disabled" => "Deleted messages in this Matrix room will no longer be announced on NetChat.",
+    "interval_pinned" => "The NetChat room bridged to this Matrix room will now be polled every <b>{seconds}s</b>.",
+    "interval_adaptive" => "The NetChat room bridged to this Matrix room will now be polled more often while it is active and less often while it is quiet.",
     "message_bridge_failed" => "Uh oh! Something went wrong while bridging that message (<code>{error}</code>). Please try again later.",
+    "message_delivery_failed" => "Sorry {user}, your message \"{message}\" could not be sent to NetChat after <b>{attempts}</b> attempt(s) (<code>{error}</code>).",
     "username_set_successfully" => "Your NetChat username for this room has been successfully set to <b>{username}</b>.",
     "username_cleared_successfully" => "Your NetChat username for this room has been successfully cleared. Your NetChat messages will now send as your Matrix display name.",
     "username_not_set" => "You do not have a NetChat username for this room.",
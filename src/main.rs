@@ -1,32 +1,68 @@
+mod appservice;
 mod commands;
 mod configuration;
 mod database;
+mod encryption;
 mod language;
 mod logging;
+mod migrations;
+#[cfg(test)]
+mod mock_netchat;
 mod netchat;
 mod permissions;
 mod secrets;
+mod stores;
 mod utilities;
 
+use appservice::GhostUsers;
 use clap::Parser;
 use configuration::Configuration;
 use database::Database;
+use encryption::DatabaseKey;
+use language::get_text;
 use logging::{log_error, log_matrix_error, log_message, LogMessageType::*};
 use matrix_sdk::event_handler::Ctx;
 use matrix_sdk::{
     config::SyncSettings,
-    room::Room,
-    ruma::events::room::{
-        member::StrippedRoomMemberEvent,
-        message::{MessageType, OriginalSyncRoomMessageEvent},
+    room::{self, Room},
+    ruma::{
+        api::{
+            appservice::Registration,
+            client::error::ErrorKind,
+            error::{FromHttpResponseError, ServerError},
+        },
+        events::{
+            room::{
+                member::StrippedRoomMemberEvent,
+                message::{
+                    MessageType, OriginalSyncRoomMessageEvent, Relation, RoomMessageEventContent,
+                },
+                redaction::OriginalSyncRoomRedactionEvent,
+            },
+            AnyMessageLikeEvent, AnyTimelineEvent, MessageLikeEvent,
+        },
+        EventId, RoomId, UserId,
     },
-    Client,
+    Client, HttpError, RumaApiError, Session,
 };
+use netchat::{ExportFormat, NetChatClient, NetChatError, NetChatMessage};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
-use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{mpsc, Arc, Mutex};
-use tokio::time::{sleep, Duration};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use stores::{BridgeStore, OutboundStore, RelayedStore, SessionStore, UsernameStore};
+use tokio::sync::{mpsc, watch, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant};
+
+const ECHO_EXPIRY_SECONDS: i64 = 300;
+const IDLE_INTERVAL_RATIO: u64 = 10;
+const QUEUE_CAPACITY: usize = 256;
+const OUTBOUND_RETRY_BASE_SECONDS: i64 = 5;
+const OUTBOUND_RETRY_MAX_SECONDS: i64 = 600;
+/// How long edits and redactions of a relayed message are still relayed.
+const RELAYED_EXPIRY_SECONDS: i64 = 7 * 24 * 60 * 60;
+const RELAYED_PRUNE_INTERVAL_SECONDS: u64 = 60 * 60;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -49,6 +85,54 @@ struct Arguments {
     /// created if it doesn't exist)
     #[arg(short, long, default_value = "netchat_bridge.db")]
     database_path: String,
+
+    /// The application service registration file
+    /// (only used when appservice mode is enabled).
+    #[arg(short, long, default_value = "registration.yaml")]
+    registration_file: String,
+
+    /// Generate a new application service registration
+    /// file with random tokens.
+    #[arg(long)]
+    generate_registration: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Export the full history of a NetChat room
+    /// to a file and exit.
+    Export {
+        room_name: String,
+        room_password: String,
+
+        /// The NetChat instance the room is on
+        /// (defaults to the configured instance).
+        #[arg(short, long)]
+        instance_url: Option<String>,
+
+        #[arg(short, long, value_enum, default_value_t = ExportFormat::Jsonl)]
+        format: ExportFormat,
+
+        /// The file to write the history to
+        /// (defaults to the room name).
+        #[arg(short, long)]
+        output_file: Option<String>,
+    },
+
+    /// Upgrade the database to the current schema and exit
+    /// (this also happens automatically on startup).
+    Migrate {
+        /// Only report what would change.
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Encrypt the stored NetChat room passwords with a new
+    /// key, save it to the key file from the secrets and exit.
+    RotateKey,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -56,225 +140,715 @@ pub struct BridgedRoomData {
     room_name: String,
     room_password: String,
     message_count: usize,
-    pending_messages: usize,
+    #[serde(default)]
+    instance_url: Option<String>,
+    #[serde(default)]
+    sent_messages: Vec<SentMessage>,
+    #[serde(default)]
+    relay_notices: bool,
+    #[serde(default)]
+    relay_redactions: bool,
+    #[serde(default)]
+    poll_interval: Option<u64>,
+    #[serde(default)]
+    last_activity: i64,
+}
+
+/// Fingerprint of a message the bridge posted to NetChat, used to
+/// recognize (and skip) its echo when the room is polled again.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct SentMessage {
+    username: String,
+    body: String,
+    sent_at: i64,
+    /// How many messages the room had when this one was sent. Only
+    /// messages from that point on can be its echo.
+    #[serde(default)]
+    message_index: usize,
+}
+
+impl BridgedRoomData {
+    /// The NetChat instance this bridge talks to, falling back
+    /// to the globally configured instance if it has no override.
+    pub fn instance_url<'a>(&'a self, bot_configuration: &'a Configuration) -> &'a str {
+        self.instance_url
+            .as_deref()
+            .unwrap_or(&bot_configuration.instance_url)
+            .trim_end_matches('/')
+    }
+
+    /// How often the NetChat room should be polled. Unless an interval has been
+    /// pinned, a room is polled more slowly the longer it has been quiet.
+    pub fn poll_interval(&self, bot_configuration: &Configuration) -> Duration {
+        if let Some(poll_interval) = self.poll_interval {
+            return Duration::from_secs(poll_interval.max(1));
+        }
+        let idle_seconds = (chrono::Utc::now().timestamp() - self.last_activity).max(0) as u64;
+        let minimum = bot_configuration.refresh_interval.max(1);
+        Duration::from_secs(
+            (idle_seconds / IDLE_INTERVAL_RATIO)
+                .clamp(minimum, bot_configuration.max_refresh_interval.max(minimum)),
+        )
+    }
+
+    /// Forgets fingerprints whose echo never showed up, so that a
+    /// later message with the same content isn't hidden by mistake.
+    pub fn prune_sent_messages(&mut self) {
+        let oldest = chrono::Utc::now().timestamp() - ECHO_EXPIRY_SECONDS;
+        self.sent_messages
+            .retain(|sent_message| sent_message.sent_at >= oldest);
+    }
+
+    /// Checks whether the message at `message_index` in the NetChat room is
+    /// the echo of one the bridge sent, consuming the matching fingerprint.
+    /// Messages that were posted before the bridge sent its copy are never echoes.
+    pub fn take_echo(
+        &mut self,
+        message_index: usize,
+        message: &NetChatMessage,
+    ) -> Option<SentMessage> {
+        let (username, body) = match message {
+            NetChatMessage::Chat { username, body, .. } => (username, body),
+            NetChatMessage::Unknown(_) => return None,
+        };
+        let index = self.sent_messages.iter().position(|sent_message| {
+            message_index >= sent_message.message_index
+                && sent_message.username.trim() == username.trim()
+                && sent_message.body.trim() == body.trim()
+        })?;
+        Some(self.sent_messages.remove(index))
+    }
+}
+
+/// A NetChat room, which may be bridged into several Matrix rooms.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NetChatRoom {
+    instance_url: String,
+    name: String,
+    password: String,
 }
 
 pub struct NetChatBridgeMessage {
-    content: String,
+    message: NetChatMessage,
     matrix_room_id: String,
 }
 
+/// A Matrix message waiting to be sent to NetChat. These are kept in the
+/// `OutboundStore` until they have been delivered, so that NetChat
+/// outages and restarts don't lose them.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct MatrixBridgeMessage {
-    netchat_room_name: String,
-    netchat_room_password: String,
+    matrix_room_id: String,
+    matrix_sender: String,
     netchat_username: String,
     netchat_message: String,
-    matrix_room_id: String,
+    #[serde(default)]
+    attempts: u32,
+    #[serde(default)]
+    next_attempt_at: i64,
+    /// Remembered in the `RelayedStore` once the message has been
+    /// delivered, so that edits and redactions of it are relayed as well.
+    #[serde(default)]
+    matrix_event_id: Option<String>,
+    /// For edits, the event that was edited. An edit is dropped
+    /// if that message never made it to NetChat.
+    #[serde(default)]
+    edited_event_id: Option<String>,
 }
 
 #[derive(Clone)]
 pub struct MatrixContext {
     bot_configuration: configuration::Configuration,
+    netchat_client: NetChatClient,
+    ghost_users: Option<GhostUsers>,
     database: Database,
-    matrix_queue_sender: Arc<Mutex<mpsc::Sender<MatrixBridgeMessage>>>,
+    bridges: BridgeStore,
+    usernames: UsernameStore,
+    outbound: OutboundStore,
+    relayed: RelayedStore,
+    matrix_queue_sender: mpsc::Sender<()>,
 }
 
 async fn receive_netchat_messages(
     netchat_queue_sender: mpsc::Sender<NetChatBridgeMessage>,
     bot_configuration: &Configuration,
-    database: Database,
+    netchat_client: NetChatClient,
+    bridges: BridgeStore,
+    mut shutdown: watch::Receiver<bool>,
 ) {
     log_message(
         Bridge,
-        &format!("Running NetChat receiver thread! Waiting for messages from NetChat..."),
+        "Running NetChat receiver thread! Waiting for messages from NetChat...",
     );
 
-    loop {
-        for (key, value) in database.iter() {
-            if key.starts_with("bridge.") {
-                let bridged_room_data =
-                    match serde_json::from_str::<BridgedRoomData>(value.as_str()) {
-                        Ok(bridged_room_data) => bridged_room_data,
-                        Err(error) => {
-                            log_error(error);
-                            continue;
-                        }
+    let semaphore = Arc::new(Semaphore::new(
+        bot_configuration.max_concurrent_polls.max(1),
+    ));
+    let mut last_polled: HashMap<NetChatRoom, Instant> = HashMap::new();
+    let mut polls: HashMap<NetChatRoom, JoinHandle<()>> = HashMap::new();
+    while !*shutdown.borrow() {
+        // new bridges are picked up within the fastest interval
+        let mut next_poll = Duration::from_secs(bot_configuration.refresh_interval.max(1));
+        polls.retain(|_, poll| !poll.is_finished());
+
+        // every NetChat room is polled once, no matter how many Matrix rooms it's bridged to
+        let mut subscribers: BTreeMap<NetChatRoom, (Vec<String>, Duration)> = BTreeMap::new();
+        for (matrix_room_id, bridged_room_data) in bridges.iter() {
+            let bridged_room_data = match bridged_room_data {
+                Ok(bridged_room_data) => bridged_room_data,
+                Err(error) => {
+                    log_error(error);
+                    continue;
+                }
+            };
+            let poll_interval = bridged_room_data.poll_interval(bot_configuration);
+            let (matrix_room_ids, room_poll_interval) = subscribers
+                .entry(NetChatRoom {
+                    instance_url: bridged_room_data
+                        .instance_url(bot_configuration)
+                        .to_string(),
+                    name: bridged_room_data.room_name,
+                    password: bridged_room_data.room_password,
+                })
+                .or_insert((Vec::new(), poll_interval));
+            matrix_room_ids.push(matrix_room_id);
+            *room_poll_interval = (*room_poll_interval).min(poll_interval);
+        }
+
+        for (netchat_room, (matrix_room_ids, poll_interval)) in subscribers {
+            if *shutdown.borrow() {
+                break;
+            }
+            // a room that is still busy with its last poll only holds up itself
+            if polls.contains_key(&netchat_room) {
+                continue;
+            }
+            if let Some(elapsed) = last_polled
+                .get(&netchat_room)
+                .map(|last_polled| last_polled.elapsed())
+            {
+                if elapsed < poll_interval {
+                    next_poll = next_poll.min(poll_interval - elapsed);
+                    continue;
+                }
+            }
+            last_polled.insert(netchat_room.clone(), Instant::now());
+            next_poll = next_poll.min(poll_interval);
+
+            let semaphore = semaphore.clone();
+            let netchat_queue_sender = netchat_queue_sender.clone();
+            let room_client = netchat_client.with_instance_url(&netchat_room.instance_url);
+            let bridges = bridges.clone();
+            let thread_netchat_room = netchat_room.clone();
+            polls.insert(
+                netchat_room,
+                tokio::spawn(async move {
+                    let _permit = match semaphore.acquire_owned().await {
+                        Ok(permit) => permit,
+                        Err(_) => return,
                     };
-                let message_count = match netchat::get_room_message_count(
-                    &bot_configuration,
-                    &bridged_room_data.room_name,
-                    &bridged_room_data.room_password,
-                )
-                .await
+                    poll_netchat_room(
+                        &netchat_queue_sender,
+                        &room_client,
+                        &bridges,
+                        &thread_netchat_room,
+                        &matrix_room_ids,
+                    )
+                    .await
+                }),
+            );
+        }
+        tokio::select! {
+            _ = sleep(next_poll) => (),
+            _ = shutdown.changed() => (),
+        }
+    }
+    for (_, poll) in polls {
+        if let Err(error) = poll.await {
+            log_error(error);
+        }
+    }
+    log_message(Bridge, "Stopped polling NetChat!");
+}
+
+async fn poll_netchat_room(
+    netchat_queue_sender: &mpsc::Sender<NetChatBridgeMessage>,
+    room_client: &NetChatClient,
+    bridges: &BridgeStore,
+    netchat_room: &NetChatRoom,
+    matrix_room_ids: &[String],
+) {
+    let message_count = match room_client
+        .get_room_message_count(&netchat_room.name, &netchat_room.password)
+        .await
+    {
+        Ok(message_count) => message_count,
+        // the rate limiter already skips this room until its backoff is over
+        Err(NetChatError::BackingOff(_)) => return,
+        Err(error @ NetChatError::RateLimited { .. }) => {
+            log_message(
+                Warning,
+                &format!(
+                    "Ratelimited by NetChat while polling {}: {error}",
+                    netchat_room.name
+                ),
+            );
+            return;
+        }
+        Err(NetChatError::Unauthorized) => {
+            log_message(
+                Warning,
+                &format!(
+                    "NetChat rejected the password for {} (bridged to {})",
+                    netchat_room.name,
+                    matrix_room_ids.join(", ")
+                ),
+            );
+            return;
+        }
+        Err(error) => {
+            log_error(error);
+            return;
+        }
+    };
+
+    // fetched at most once, and only if one of the bridges is behind
+    let mut room_messages: Option<Vec<String>> = None;
+    for matrix_room_id in matrix_room_ids {
+        let mut bridged_room_data = match bridges.get(matrix_room_id) {
+            Ok(Some(bridged_room_data)) => bridged_room_data,
+            Ok(None) => continue,
+            Err(error) => {
+                log_error(error);
+                continue;
+            }
+        };
+        if bridged_room_data.message_count > message_count {
+            if let Err(error) = bridges.update(matrix_room_id, |bridged_room_data| {
+                bridged_room_data.message_count = message_count;
+                // the room has been cleared, so echoes start over as well
+                for sent_message in &mut bridged_room_data.sent_messages {
+                    sent_message.message_index = sent_message.message_index.min(message_count);
+                }
+            }) {
+                log_error(error);
+            }
+            continue;
+        }
+        bridged_room_data.prune_sent_messages();
+        let mut echoes = Vec::new();
+        let has_new_messages = message_count > bridged_room_data.message_count;
+        // everything that is relayed has to be counted, including messages
+        // that were posted after messageCount but before rawMessages
+        let mut new_message_count = message_count;
+        if has_new_messages {
+            if room_messages.is_none() {
+                room_messages = match room_client
+                    .get_room_messages(&netchat_room.name, &netchat_room.password)
+                    .await
                 {
-                    Ok(message_count) => message_count,
+                    Ok(room_messages) => Some(room_messages),
+                    Err(NetChatError::BackingOff(_) | NetChatError::RateLimited { .. }) => return,
                     Err(error) => {
                         log_error(error);
-                        continue;
+                        return;
                     }
                 };
-                let mut bridged_room_data = match database.get(&key) {
-                    Ok(value) => match value {
-                        Some(value) => {
-                            match serde_json::from_str::<BridgedRoomData>(value.as_str()) {
-                                Ok(bridged_room_data) => bridged_room_data,
-                                Err(error) => {
-                                    log_error(error);
-                                    continue;
-                                }
-                            }
-                        }
-                        None => continue,
-                    },
-                    Err(error) => {
-                        log_error(error);
+            }
+            let room_messages = room_messages.as_deref().unwrap_or_default();
+            if room_messages.len() > bridged_room_data.message_count {
+                new_message_count = room_messages.len();
+                // echoes are only skipped for the bridge that sent them,
+                // every other Matrix room still needs to see the message
+                for (index, message) in room_messages
+                    .iter()
+                    .enumerate()
+                    .skip(bridged_room_data.message_count)
+                {
+                    let message = NetChatMessage::parse(message);
+                    if let Some(echo) = bridged_room_data.take_echo(index, &message) {
+                        echoes.push(echo);
                         continue;
                     }
-                };
-                if bridged_room_data.message_count > message_count {
-                    bridged_room_data.message_count = message_count;
-                    match database.set(
-                        &key,
-                        serde_json::to_string(&bridged_room_data).unwrap().as_str(),
-                    ) {
-                        Ok(_) => (),
-                        Err(error) => {
-                            log_error(error);
-                        }
-                    };
-                    continue;
-                }
-                bridged_room_data.message_count += bridged_room_data.pending_messages;
-                bridged_room_data.pending_messages = 0;
-                if message_count > bridged_room_data.message_count {
-                    let room_messages = match netchat::get_room_messages(
-                        &bot_configuration,
-                        &bridged_room_data.room_name,
-                        &bridged_room_data.room_password,
-                    )
-                    .await
+                    if let Err(error) = netchat_queue_sender
+                        .send(NetChatBridgeMessage {
+                            message,
+                            matrix_room_id: matrix_room_id.to_string(),
+                        })
+                        .await
                     {
-                        Ok(room_messages) => room_messages,
-                        Err(error) => {
-                            log_error(error);
-                            continue;
-                        }
-                    };
-                    if room_messages.len() > bridged_room_data.message_count {
-                        for message in &room_messages[bridged_room_data.message_count..] {
-                            let mut processed_message = message.to_string();
-                            processed_message.insert_str("[1970-01-01 00:00:00]".len(), "</b>");
-                            processed_message.insert_str(0, "<b>");
-                            netchat_queue_sender
-                                .send(NetChatBridgeMessage {
-                                    content: processed_message,
-                                    matrix_room_id: key["bridge.".len()..].to_string(),
-                                })
-                                .unwrap();
-                        }
+                        log_error(error);
                     }
+                }
+            }
+        }
 
-                    bridged_room_data.message_count = message_count;
+        // messages may have been sent to NetChat in the meantime,
+        // so only the fingerprints of the echoes seen here are dropped
+        let now = chrono::Utc::now().timestamp();
+        if let Err(error) = bridges.update(matrix_room_id, |bridged_room_data| {
+            for echo in &echoes {
+                if let Some(index) = bridged_room_data
+                    .sent_messages
+                    .iter()
+                    .position(|sent_message| sent_message == echo)
+                {
+                    bridged_room_data.sent_messages.remove(index);
                 }
-                match database.set(
-                    &key,
-                    serde_json::to_string(&bridged_room_data).unwrap().as_str(),
-                ) {
-                    Ok(_) => (),
-                    Err(error) => {
-                        log_error(error);
-                        continue;
-                    }
-                };
-            };
+            }
+            bridged_room_data.prune_sent_messages();
+            if has_new_messages {
+                bridged_room_data.message_count = new_message_count;
+                bridged_room_data.last_activity = now;
+            }
+        }) {
+            log_error(error);
         }
-        sleep(std::time::Duration::from_secs(
-            bot_configuration.refresh_interval,
-        ))
-        .await;
     }
 }
 
 async fn bridge_netchat_messages(
-    netchat_queue_receiver: mpsc::Receiver<NetChatBridgeMessage>,
+    mut netchat_queue_receiver: mpsc::Receiver<NetChatBridgeMessage>,
     client: Client,
+    ghost_users: Option<GhostUsers>,
 ) {
     log_message(
         Bridge,
-        &format!(
-            "Running NetChat -> Matrix thread! Waiting for messages from the NetChat receiver..."
-        ),
+        "Running NetChat -> Matrix thread! Waiting for messages from the NetChat receiver...",
     );
 
-    loop {
-        let bridge_message = netchat_queue_receiver.recv().unwrap();
-        match client
+    // the queue only closes once the NetChat receiver has stopped,
+    // so everything it already fetched is still sent to Matrix
+    while let Some(bridge_message) = netchat_queue_receiver.recv().await {
+        if let Some(joined_room) = client
             .joined_rooms()
             .iter()
             .find(|item| item.room_id().as_str() == bridge_message.matrix_room_id)
         {
-            Some(joined_room) => {
-                utilities::send_html_message(&joined_room, &bridge_message.content).await
+            if let (Some(ghost_users), NetChatMessage::Chat { username, body, .. }) =
+                (&ghost_users, &bridge_message.message)
+            {
+                match ghost_users
+                    .send_message(
+                        joined_room,
+                        username,
+                        &RoomMessageEventContent::text_plain(body),
+                    )
+                    .await
+                {
+                    Ok(_) => continue,
+                    Err(error) => log_error(error),
+                }
+            }
+            match bridge_message.message.to_html() {
+                Some(html) => utilities::send_html_message(joined_room, &html).await,
+                None => {
+                    utilities::send_plain_message(joined_room, &bridge_message.message.to_plain())
+                        .await
+                }
             }
-            None => (),
         }
     }
+    log_message(Bridge, "Stopped the NetChat -> Matrix thread!");
 }
 
 async fn bridge_matrix_messages(
-    matrix_queue_receiver: mpsc::Receiver<MatrixBridgeMessage>,
-    bot_configuration: &Configuration,
+    mut matrix_queue_receiver: mpsc::Receiver<()>,
+    client: Client,
+    bot_configuration: Configuration,
+    netchat_client: NetChatClient,
     database: Database,
+    bridges: BridgeStore,
+    mut shutdown: watch::Receiver<bool>,
 ) {
     log_message(
         Bridge,
-        &format!(
-            "Running Matrix -> NetChat thread! Waiting for messages from the on_room_message event..."
-        ),
+        "Running Matrix -> NetChat thread! Waiting for messages from the on_room_message event...",
     );
+    let outbound = OutboundStore::new(&database);
+    let relayed = RelayedStore::new(&database);
 
+    let mut next_prune = Instant::now();
     loop {
-        let bridge_message = matrix_queue_receiver.recv().unwrap();
-        match netchat::send_message(
+        if Instant::now() >= next_prune {
+            if let Err(error) =
+                relayed.prune(chrono::Utc::now().timestamp() - RELAYED_EXPIRY_SECONDS)
+            {
+                log_error(error);
+            }
+            next_prune = Instant::now() + Duration::from_secs(RELAYED_PRUNE_INTERVAL_SECONDS);
+        }
+        let (failed_messages, next_attempt_at) = send_outbound_messages(
             &bot_configuration,
-            &bridge_message.netchat_room_name,
-            &bridge_message.netchat_room_password,
-            &bridge_message.netchat_username,
-            &bridge_message.netchat_message,
+            &netchat_client,
+            &outbound,
+            &relayed,
+            &bridges,
         )
-        .await
-        {
-            Ok(_) => (),
+        .await;
+        for (bridge_message, error) in failed_messages {
+            notify_failed_message(&client, &bridge_message, &error).await;
+        }
+        // whatever is still waiting for a retry stays in the
+        // database and is picked up again on the next start
+        if *shutdown.borrow() {
+            break;
+        }
+
+        let retry_delay = match next_attempt_at {
+            Some(next_attempt_at) => {
+                Duration::from_secs((next_attempt_at - chrono::Utc::now().timestamp()).max(1) as u64)
+            }
+            None => Duration::from_secs(OUTBOUND_RETRY_MAX_SECONDS as u64),
+        };
+        tokio::select! {
+            wakeup = matrix_queue_receiver.recv() => if wakeup.is_none() {
+                break;
+            },
+            _ = sleep(retry_delay) => (),
+            _ = shutdown.changed(), if !*shutdown.borrow() => (),
+        }
+    }
+    log_message(Bridge, "Stopped the Matrix -> NetChat thread!");
+}
+
+/// Sends every outbound message that is due, oldest first. A room's queue
+/// stops at the first message that has to be retried, so that NetChat
+/// always receives a room's messages in order.
+///
+/// Returns the messages that were given up on (with the last error),
+/// as well as when the next retry is due.
+async fn send_outbound_messages(
+    bot_configuration: &Configuration,
+    netchat_client: &NetChatClient,
+    outbound: &OutboundStore,
+    relayed: &RelayedStore,
+    bridges: &BridgeStore,
+) -> (Vec<(MatrixBridgeMessage, NetChatError)>, Option<i64>) {
+    let mut failed_messages = Vec::new();
+    let mut next_attempt_at: Option<i64> = None;
+    let mut waiting_rooms: Vec<String> = Vec::new();
+    for (key, bridge_message) in outbound.iter() {
+        let mut bridge_message = match bridge_message {
+            Ok(bridge_message) => bridge_message,
             Err(error) => {
                 log_error(error);
+                continue;
             }
+        };
+        if waiting_rooms.contains(&bridge_message.matrix_room_id) {
+            continue;
+        }
+        let now = chrono::Utc::now().timestamp();
+        if bridge_message.next_attempt_at > now {
+            next_attempt_at = Some(
+                next_attempt_at.map_or(bridge_message.next_attempt_at, |next_attempt_at| {
+                    next_attempt_at.min(bridge_message.next_attempt_at)
+                }),
+            );
+            waiting_rooms.push(bridge_message.matrix_room_id);
+            continue;
         }
-        match database.get(&format!("bridge.{}", bridge_message.matrix_room_id)) {
-            Ok(value) => match value {
-                Some(value) => match serde_json::from_str::<BridgedRoomData>(value.as_str()) {
-                    Ok(bridged_room_data) => {
-                        let mut bridged_room_data = bridged_room_data;
-                        bridged_room_data.pending_messages += 1;
-                        match database.set(
-                            &format!("bridge.{}", bridge_message.matrix_room_id),
-                            serde_json::to_string(&bridged_room_data).unwrap().as_str(),
-                        ) {
-                            Ok(_) => (),
-                            Err(error) => {
-                                log_error(error);
-                            }
-                        };
+        let bridged_room_data = match bridges.get(&bridge_message.matrix_room_id) {
+            Ok(Some(bridged_room_data)) => bridged_room_data,
+            Ok(None) => {
+                // the room has been unbridged since the message was queued
+                if let Err(error) = outbound.remove(&key) {
+                    log_error(error);
+                }
+                continue;
+            }
+            Err(error) => {
+                log_error(error);
+                continue;
+            }
+        };
+        if let Some(edited_event_id) = &bridge_message.edited_event_id {
+            // the edited message was queued before the edit, so by now
+            // it has either been delivered or been given up on
+            match relayed.contains(&bridge_message.matrix_room_id, edited_event_id) {
+                Ok(true) => (),
+                Ok(false) => {
+                    if let Err(error) = outbound.remove(&key) {
+                        log_error(error);
                     }
-                    Err(error) => {
-                        log_error(&error);
+                    continue;
+                }
+                Err(error) => {
+                    log_error(error);
+                    continue;
+                }
+            }
+        }
+
+        let error = match send_bridge_message(
+            bot_configuration,
+            netchat_client,
+            bridges,
+            &bridged_room_data,
+            &bridge_message,
+        )
+        .await
+        {
+            Ok(_) => {
+                if let Err(error) = outbound.remove(&key) {
+                    log_error(error);
+                }
+                if let Some(matrix_event_id) = &bridge_message.matrix_event_id {
+                    if let Err(error) =
+                        relayed.insert(&bridge_message.matrix_room_id, matrix_event_id, now)
+                    {
+                        log_error(error);
                     }
-                },
-                None => (),
-            },
-            Err(error) => {
-                log_error(&error);
+                }
+                continue;
+            }
+            Err(NetChatError::BackingOff(remaining)) => {
+                // nothing was sent, so this doesn't count as an attempt
+                bridge_message.next_attempt_at = now + remaining.as_secs().max(1) as i64;
+                next_attempt_at = Some(
+                    next_attempt_at.map_or(bridge_message.next_attempt_at, |next_attempt_at| {
+                        next_attempt_at.min(bridge_message.next_attempt_at)
+                    }),
+                );
+                if let Err(error) = outbound.set(&key, &bridge_message) {
+                    log_error(error);
+                }
+                waiting_rooms.push(bridge_message.matrix_room_id);
+                continue;
             }
+            Err(error) => error,
         };
+        bridge_message.attempts += 1;
+        if matches!(error, NetChatError::Unauthorized)
+            || bridge_message.attempts >= bot_configuration.outbound_max_attempts
+        {
+            if let Err(error) = outbound.remove(&key) {
+                log_error(error);
+            }
+            failed_messages.push((bridge_message, error));
+            continue;
+        }
+
+        let mut retry_delay = (OUTBOUND_RETRY_BASE_SECONDS
+            << (bridge_message.attempts - 1).min(16))
+        .min(OUTBOUND_RETRY_MAX_SECONDS);
+        if let NetChatError::RateLimited {
+            retry_after: Some(retry_after),
+        } = error
+        {
+            retry_delay = retry_delay.max(retry_after.as_secs() as i64);
+        }
+        log_message(
+            Warning,
+            &format!(
+                "Unable to send message to {} (attempt {}), retrying in {retry_delay}s: {error}",
+                bridged_room_data.room_name, bridge_message.attempts
+            ),
+        );
+        bridge_message.next_attempt_at = now + retry_delay;
+        next_attempt_at = Some(
+            next_attempt_at.map_or(bridge_message.next_attempt_at, |next_attempt_at| {
+                next_attempt_at.min(bridge_message.next_attempt_at)
+            }),
+        );
+        if let Err(error) = outbound.set(&key, &bridge_message) {
+            log_error(error);
+        }
+        waiting_rooms.push(bridge_message.matrix_room_id);
+    }
+    (failed_messages, next_attempt_at)
+}
+
+async fn send_bridge_message(
+    bot_configuration: &Configuration,
+    netchat_client: &NetChatClient,
+    bridges: &BridgeStore,
+    bridged_room_data: &BridgedRoomData,
+    bridge_message: &MatrixBridgeMessage,
+) -> Result<(), NetChatError> {
+    let sent_message = SentMessage {
+        username: bridge_message.netchat_username.to_string(),
+        body: bridge_message.netchat_message.to_string(),
+        sent_at: chrono::Utc::now().timestamp(),
+        message_index: bridged_room_data.message_count,
+    };
+    // The fingerprint is recorded before sending so that a poll
+    // happening mid-request can already recognize the echo.
+    // it also counts as activity, so that replies from NetChat are picked up quickly
+    if let Err(error) = bridges.update(&bridge_message.matrix_room_id, |bridged_room_data| {
+        bridged_room_data.sent_messages.push(sent_message.clone());
+        bridged_room_data.last_activity = sent_message.sent_at;
+    }) {
+        log_error(error);
+    }
+    let result = netchat_client
+        .with_instance_url(bridged_room_data.instance_url(bot_configuration))
+        .send_message(
+            &bridged_room_data.room_name,
+            &bridged_room_data.room_password,
+            &bridge_message.netchat_username,
+            &bridge_message.netchat_message,
+        )
+        .await;
+    if result.is_err() {
+        if let Err(error) = bridges.update(&bridge_message.matrix_room_id, |bridged_room_data| {
+            bridged_room_data
+                .sent_messages
+                .retain(|item| *item != sent_message)
+        }) {
+            log_error(error);
+        }
+    }
+    result
+}
+
+async fn notify_failed_message(
+    client: &Client,
+    bridge_message: &MatrixBridgeMessage,
+    error: &NetChatError,
+) {
+    log_message(
+        Error,
+        &format!(
+            "Giving up on a message from {} in {} after {} attempt(s): {error}",
+            bridge_message.matrix_sender, bridge_message.matrix_room_id, bridge_message.attempts
+        ),
+    );
+    let room = match RoomId::parse(&bridge_message.matrix_room_id) {
+        Ok(room_id) => match client.get_joined_room(&room_id) {
+            Some(room) => room,
+            None => return,
+        },
+        Err(error) => {
+            log_error(error);
+            return;
+        }
+    };
+    utilities::send_html_message(
+        &room,
+        &get_text("message_delivery_failed")
+            .replace("{user}", &bridge_message.matrix_sender)
+            .replace(
+                "{message}",
+                &utilities::escape_html(&bridge_message.netchat_message),
+            )
+            .replace("{attempts}", &bridge_message.attempts.to_string())
+            .replace("{error}", &utilities::escape_html(&error.to_string())),
+    )
+    .await;
+}
+
+fn load_secrets(secrets_file: &str) -> secrets::Secrets {
+    match secrets::Secrets::from_json_file(Path::new(secrets_file)) {
+        Ok(bot_secrets) => bot_secrets,
+        Err(error) => {
+            log_message(
+                Error,
+                &format!("Unable to parse {secrets_file} as JSON file: {error}"),
+            );
+            std::process::exit(1);
+        }
     }
 }
 
@@ -307,20 +881,6 @@ async fn main() -> anyhow::Result<()> {
         };
         std::process::exit(0);
     }
-    let bot_secrets =
-        match secrets::Secrets::from_json_file(Path::new(arguments.secrets_file.as_str())) {
-            Ok(bot_secrets) => bot_secrets,
-            Err(error) => {
-                log_message(
-                    Error,
-                    &format!(
-                        "Unable to parse {} as JSON file: {error}",
-                        arguments.secrets_file
-                    ),
-                );
-                std::process::exit(1);
-            }
-        };
     let bot_configuration = match configuration::Configuration::from_json_file(Path::new(
         arguments.configuration_file.as_str(),
     )) {
@@ -336,26 +896,224 @@ async fn main() -> anyhow::Result<()> {
             configuration::Configuration::default()
         }
     };
+    if arguments.generate_registration {
+        // only the secrets are needed, the database isn't touched
+        let bot_secrets = load_secrets(&arguments.secrets_file);
+        let sender_localpart = bot_secrets
+            .username
+            .trim_start_matches('@')
+            .split(':')
+            .next()
+            .unwrap_or_default();
+        match appservice::generate_registration(&bot_configuration, sender_localpart).and_then(
+            |registration| {
+                appservice::save_registration(
+                    &registration,
+                    Path::new(&arguments.registration_file),
+                )
+            },
+        ) {
+            Ok(_) => log_message(
+                Bot,
+                &format!(
+                    "Successfully saved new registration to {}!",
+                    arguments.registration_file
+                ),
+            ),
+            Err(error) => log_message(
+                Error,
+                &format!(
+                    "Unable to save registration to {}: {error}",
+                    arguments.registration_file
+                ),
+            ),
+        };
+        std::process::exit(0);
+    }
 
-    let database = match Database::new(&arguments.database_path) {
-        Ok(database) => database,
-        Err(error) => {
+    if let Some(Command::Export {
+        room_name,
+        room_password,
+        instance_url,
+        format,
+        output_file,
+    }) = &arguments.command
+    {
+        let output_file = match output_file {
+            Some(output_file) => output_file.to_string(),
+            None => format!("{room_name}.{}", format.extension()),
+        };
+        match export_room(
+            &bot_configuration,
+            room_name,
+            room_password,
+            instance_url.as_deref(),
+            *format,
+            Path::new(&output_file),
+        )
+        .await
+        {
+            Ok(message_count) => log_message(
+                Bot,
+                &format!("Successfully exported {message_count} message(s) to {output_file}!"),
+            ),
+            Err(error) => {
+                log_message(Error, &format!("Unable to export {room_name}: {error}"));
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+    let database = match Database::new(&arguments.database_path) {
+        Ok(database) => database,
+        Err(error) => {
             log_message(Error, &format!("Unable to open database: {error}"));
             std::process::exit(1);
         }
     };
-    let (netchat_tx, netchat_rx): (Sender<NetChatBridgeMessage>, Receiver<NetChatBridgeMessage>) =
-        mpsc::channel();
-    let (matrix_tx, matrix_rx): (Sender<MatrixBridgeMessage>, Receiver<MatrixBridgeMessage>) =
-        mpsc::channel();
+    let dry_run = matches!(arguments.command, Some(Command::Migrate { dry_run: true }));
+    match migrations::migrate(&database, dry_run) {
+        Ok(report) => {
+            for (key, descriptions) in &report.changes {
+                log_message(
+                    Bot,
+                    &format!(
+                        "{} {key}: {}",
+                        if dry_run { "Would migrate" } else { "Migrated" },
+                        descriptions.join(", ")
+                    ),
+                );
+            }
+            if report.from_version != report.to_version {
+                log_message(
+                    Bot,
+                    &format!(
+                        "{} database from schema version {} to {} ({} record(s) changed)",
+                        if dry_run { "Would upgrade" } else { "Upgraded" },
+                        report.from_version,
+                        report.to_version,
+                        report.changes.len()
+                    ),
+                );
+            }
+        }
+        Err(error) => {
+            log_message(Error, &format!("Unable to migrate database: {error}"));
+            std::process::exit(1);
+        }
+    }
+    if matches!(arguments.command, Some(Command::Migrate { .. })) {
+        if let Err(error) = database.flush().await {
+            log_message(Error, &format!("Unable to flush database: {error}"));
+            std::process::exit(1);
+        }
+        std::process::exit(0);
+    }
+    let bot_secrets = load_secrets(&arguments.secrets_file);
+    let database_key = match DatabaseKey::load(&bot_secrets) {
+        Ok(database_key) => database_key,
+        Err(error) => {
+            log_message(Error, &format!("Unable to load database key: {error}"));
+            std::process::exit(1);
+        }
+    };
+    if let Some(Command::RotateKey) = arguments.command {
+        match rotate_database_key(&bot_secrets, &database, database_key).await {
+            Ok(count) => log_message(
+                Bot,
+                &format!("Successfully re-encrypted {count} bridge(s) with a new database key!"),
+            ),
+            Err(error) => {
+                log_message(Error, &format!("Unable to rotate database key: {error}"));
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+    match &database_key {
+        Some(database_key) => {
+            match BridgeStore::new(&database, Some(database_key.clone()))
+                .reencrypt(database_key, true)
+            {
+                Ok(0) => (),
+                Ok(count) => log_message(
+                    Bot,
+                    &format!("Encrypted the room passwords of {count} bridge(s)"),
+                ),
+                Err(error) => {
+                    log_message(Error, &format!("Unable to encrypt room passwords: {error}"));
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => log_message(
+            Warning,
+            "No database key has been configured, NetChat room passwords are stored unencrypted!",
+        ),
+    }
+    let netchat_client = match NetChatClient::new(&bot_configuration) {
+        Ok(netchat_client) => netchat_client,
+        Err(error) => {
+            log_message(Error, &format!("Unable to build NetChat client: {error}"));
+            std::process::exit(1);
+        }
+    };
+
+    let registration = if bot_configuration.appservice_mode {
+        if let Err(error) = appservice::check_ghost_user_prefix(&bot_configuration) {
+            log_message(Error, &format!("Invalid appservice configuration: {error}"));
+            std::process::exit(1);
+        }
+        match appservice::load_registration(Path::new(&arguments.registration_file)) {
+            Ok(registration) => Some(registration),
+            Err(error) => {
+                log_message(
+                    Error,
+                    &format!(
+                        "Unable to parse {} as YAML file: {error}",
+                        arguments.registration_file
+                    ),
+                );
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    let ghost_users = registration.as_ref().map(|registration| {
+        GhostUsers::new(
+            &bot_secrets.homeserver_url,
+            &registration.as_token,
+            &bot_configuration.ghost_user_prefix,
+        )
+    });
+    let (netchat_tx, netchat_rx) = mpsc::channel(QUEUE_CAPACITY);
+    let (matrix_tx, matrix_rx) = mpsc::channel(QUEUE_CAPACITY);
+    let store_path = if bot_configuration.encryption_enabled() {
+        Some(Path::new(&arguments.database_path).with_extension("store"))
+    } else {
+        if bot_configuration.enable_encryption {
+            log_message(
+                Warning,
+                "End-to-end encryption is not supported in appservice mode, encrypted rooms will not be bridged!",
+            );
+        }
+        None
+    };
     login_and_sync(
-        bot_secrets.homeserver_url,
-        &bot_secrets.username,
-        &bot_secrets.password,
+        &bot_secrets,
+        store_path,
+        registration,
         MatrixContext {
             bot_configuration,
+            netchat_client,
+            ghost_users,
+            bridges: BridgeStore::new(&database, database_key),
+            usernames: UsernameStore::new(&database),
+            outbound: OutboundStore::new(&database),
+            relayed: RelayedStore::new(&database),
             database,
-            matrix_queue_sender: Arc::new(Mutex::new(matrix_tx)),
+            matrix_queue_sender: matrix_tx,
         },
         netchat_tx,
         netchat_rx,
@@ -365,42 +1123,228 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn login_and_sync(
-    homeserver_url: String,
-    username: &str,
-    password: &str,
-    matrix_context: MatrixContext,
-    netchat_queue_sender: mpsc::Sender<NetChatBridgeMessage>,
-    netchat_queue_receiver: mpsc::Receiver<NetChatBridgeMessage>,
-    matrix_queue_receiver: mpsc::Receiver<MatrixBridgeMessage>,
-) -> anyhow::Result<()> {
+async fn export_room(
+    bot_configuration: &Configuration,
+    room_name: &str,
+    room_password: &str,
+    instance_url: Option<&str>,
+    format: ExportFormat,
+    output_file: &Path,
+) -> Result<usize, String> {
+    let mut netchat_client = match NetChatClient::new(bot_configuration) {
+        Ok(netchat_client) => netchat_client,
+        Err(error) => return Err(error.to_string()),
+    };
+    if let Some(instance_url) = instance_url {
+        netchat_client = netchat_client.with_instance_url(instance_url.trim_end_matches('/'));
+    }
+    let raw_messages = match netchat_client
+        .get_room_messages(room_name, room_password)
+        .await
+    {
+        Ok(raw_messages) => raw_messages,
+        Err(error) => return Err(error.to_string()),
+    };
+    match std::fs::write(output_file, netchat::export_messages(&raw_messages, format)) {
+        Ok(_) => Ok(raw_messages.len()),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+/// The new key is saved next to the key file until every bridge has been
+/// re-encrypted, so that neither key gets lost if anything goes wrong.
+async fn rotate_database_key(
+    bot_secrets: &secrets::Secrets,
+    database: &Database,
+    database_key: Option<DatabaseKey>,
+) -> Result<usize, String> {
+    let key_file = match (&bot_secrets.database_key, &bot_secrets.database_key_file) {
+        (None, Some(key_file)) => PathBuf::from(key_file),
+        _ => {
+            return Err(
+                "the key can only be rotated if it's read from database_key_file".to_string(),
+            )
+        }
+    };
+    let new_key = DatabaseKey::generate();
+    let new_key_file = PathBuf::from(format!("{}.new", key_file.display()));
+    new_key.save(&new_key_file)?;
+    let count = match BridgeStore::new(database, database_key).reencrypt(&new_key, false) {
+        Ok(count) => count,
+        Err(error) => return Err(error.to_string()),
+    };
+    database.flush().await?;
+    match std::fs::rename(&new_key_file, &key_file) {
+        Ok(_) => Ok(count),
+        Err(error) => Err(format!(
+            "unable to move {} to {}: {error}",
+            new_key_file.display(),
+            key_file.display()
+        )),
+    }
+}
+
+async fn build_client(
+    bot_secrets: &secrets::Secrets,
+    store_path: Option<&Path>,
+    appservice_mode: bool,
+) -> Client {
     #[allow(unused_mut)]
-    let mut client_builder = Client::builder().homeserver_url(&homeserver_url);
-    let client = match client_builder.build().await {
+    let mut client_builder = Client::builder().homeserver_url(&bot_secrets.homeserver_url);
+    if let Some(store_path) = store_path {
+        client_builder =
+            match client_builder.sled_store(store_path, bot_secrets.store_passphrase.as_deref()) {
+                Ok(client_builder) => client_builder,
+                Err(error) => {
+                    log_message(
+                        MatrixError,
+                        &format!("Unable to open crypto store: {error}"),
+                    );
+                    std::process::exit(1);
+                }
+            };
+    }
+    if appservice_mode {
+        client_builder = client_builder.appservice_mode();
+    }
+    match client_builder.build().await {
         Ok(client) => client,
         Err(error) => {
             log_message(MatrixError, &format!("Unable to build client: {error}"));
             std::process::exit(1);
         }
-    };
+    }
+}
 
-    log_message(
-        Matrix,
-        &format!("Logging in as {} on {}...", &username, &homeserver_url),
-    );
-    match client
-        .login_username(username, password)
-        .device_id("NETCHATBRIDGE")
-        .initial_device_display_name("NetChat Bridge")
-        .send()
-        .await
-    {
-        Ok(_) => (),
+/// Returns the session saved by a previous password login,
+/// unless it belongs to a different account.
+fn get_saved_session(
+    sessions: &SessionStore,
+    username: &str,
+    homeserver_url: &str,
+) -> Option<Session> {
+    let session = match sessions.get() {
+        Ok(Some(session)) => session,
+        Ok(None) => return None,
         Err(error) => {
-            log_message(MatrixError, &format!("Unable to log in: {error}"));
-            std::process::exit(1);
+            log_error(format!("Unable to read saved session: {error}"));
+            return None;
         }
     };
+    match appservice::bot_user_id(username, homeserver_url) {
+        Ok(user_id) if user_id == session.user_id => Some(session),
+        _ => None,
+    }
+}
+
+async fn login_and_sync(
+    bot_secrets: &secrets::Secrets,
+    store_path: Option<PathBuf>,
+    registration: Option<Registration>,
+    matrix_context: MatrixContext,
+    netchat_queue_sender: mpsc::Sender<NetChatBridgeMessage>,
+    netchat_queue_receiver: mpsc::Receiver<NetChatBridgeMessage>,
+    matrix_queue_receiver: mpsc::Receiver<()>,
+) -> anyhow::Result<()> {
+    let (homeserver_url, username, password) = (
+        &bot_secrets.homeserver_url,
+        &bot_secrets.username,
+        &bot_secrets.password,
+    );
+    let mut client = build_client(bot_secrets, store_path.as_deref(), registration.is_some()).await;
+    match &registration {
+        Some(registration) => {
+            log_message(
+                Matrix,
+                &format!(
+                    "Authenticating as {} on {} with the appservice token...",
+                    &username, &homeserver_url
+                ),
+            );
+            let user_id = match appservice::bot_user_id(username, homeserver_url) {
+                Ok(user_id) => user_id,
+                Err(error) => {
+                    log_message(MatrixError, &format!("Unable to log in: {error}"));
+                    std::process::exit(1);
+                }
+            };
+            if let Err(error) = client
+                .restore_login(Session {
+                    access_token: registration.as_token.to_string(),
+                    refresh_token: None,
+                    user_id,
+                    device_id: "NETCHATBRIDGE".into(),
+                })
+                .await
+            {
+                log_message(MatrixError, &format!("Unable to log in: {error}"));
+                std::process::exit(1);
+            }
+        }
+        None => {
+            let sessions = SessionStore::new(&matrix_context.database);
+            let mut restored = false;
+            if let Some(session) = get_saved_session(&sessions, username, homeserver_url) {
+                log_message(
+                    Matrix,
+                    &format!(
+                        "Restoring saved session for {} on {}...",
+                        &username, &homeserver_url
+                    ),
+                );
+                if let Err(error) = client.restore_login(session).await {
+                    log_message(MatrixError, &format!("Unable to restore session: {error}"));
+                    std::process::exit(1);
+                }
+                match client.whoami().await {
+                    Ok(_) => restored = true,
+                    Err(HttpError::Api(FromHttpResponseError::Server(ServerError::Known(
+                        RumaApiError::ClientApi(error),
+                    )))) if matches!(error.kind, ErrorKind::UnknownToken { .. }) => {
+                        log_message(
+                            Matrix,
+                            "The saved session was rejected, falling back to password login...",
+                        );
+                        if let Err(error) = sessions.remove() {
+                            log_error(format!("Unable to remove saved session: {error}"));
+                        }
+                        // the rejected session is already bound to this client, and
+                        // its store has to be closed before it can be opened again
+                        drop(client);
+                        client = build_client(bot_secrets, store_path.as_deref(), false).await;
+                    }
+                    Err(error) => {
+                        log_message(MatrixError, &format!("Unable to restore session: {error}"));
+                        std::process::exit(1);
+                    }
+                }
+            }
+            if !restored {
+                log_message(
+                    Matrix,
+                    &format!("Logging in as {} on {}...", &username, &homeserver_url),
+                );
+                match client
+                    .login_username(username, password)
+                    .device_id("NETCHATBRIDGE")
+                    .initial_device_display_name("NetChat Bridge")
+                    .send()
+                    .await
+                {
+                    Ok(_) => (),
+                    Err(error) => {
+                        log_message(MatrixError, &format!("Unable to log in: {error}"));
+                        std::process::exit(1);
+                    }
+                };
+                if let Some(session) = client.session() {
+                    if let Err(error) = sessions.set(&session) {
+                        log_error(format!("Unable to save session: {error}"));
+                    }
+                }
+            }
+        }
+    }
     log_message(
         Matrix,
         &format!(
@@ -409,38 +1353,136 @@ async fn login_and_sync(
         ),
     );
 
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
     let thread_bot_configuration = matrix_context.bot_configuration.clone();
-    let thread_database = matrix_context.database.clone();
-    tokio::spawn(async move {
+    let thread_netchat_client = matrix_context.netchat_client.clone();
+    let thread_bridges = matrix_context.bridges.clone();
+    let thread_shutdown = shutdown_receiver.clone();
+    let netchat_receiver = tokio::spawn(async move {
         receive_netchat_messages(
             netchat_queue_sender,
             &thread_bot_configuration,
-            thread_database,
+            thread_netchat_client,
+            thread_bridges,
+            thread_shutdown,
         )
         .await
     });
     let thread_client = client.clone();
-    tokio::spawn(async { bridge_netchat_messages(netchat_queue_receiver, thread_client).await });
+    let thread_ghost_users = matrix_context.ghost_users.clone();
+    let netchat_bridge = tokio::spawn(async {
+        bridge_netchat_messages(netchat_queue_receiver, thread_client, thread_ghost_users).await
+    });
+    let thread_client = client.clone();
     let thread_bot_configuration = matrix_context.bot_configuration.clone();
+    let thread_netchat_client = matrix_context.netchat_client.clone();
     let thread_database = matrix_context.database.clone();
-    tokio::spawn(async move {
+    let thread_bridges = matrix_context.bridges.clone();
+    let matrix_bridge = tokio::spawn(async move {
         bridge_matrix_messages(
             matrix_queue_receiver,
-            &thread_bot_configuration,
+            thread_client,
+            thread_bot_configuration,
+            thread_netchat_client,
             thread_database,
+            thread_bridges,
+            shutdown_receiver,
         )
         .await
     });
     log_message(Bridge, "All threads have been spawned!");
 
+    let bot_configuration = matrix_context.bot_configuration.clone();
+    let database = matrix_context.database.clone();
     client.add_event_handler_context(matrix_context);
     client.add_event_handler(on_stripped_state_member);
-    client.sync_once(SyncSettings::default()).await.unwrap();
-    client.add_event_handler(on_room_message);
-    let settings = SyncSettings::default().token(client.sync_token().await.unwrap());
-    client.sync(settings).await?;
+    let mut matrix_receiver = tokio::spawn(async move {
+        // The message handlers are only added once the bot has caught up,
+        // so that messages from before the start aren't bridged.
+        match &registration {
+            Some(_) => appservice::load_joined_rooms(&client).await?,
+            None => {
+                if let Err(error) = client.sync_once(SyncSettings::default()).await {
+                    return Err(error.to_string());
+                }
+            }
+        }
+        client.add_event_handler(on_room_message);
+        client.add_event_handler(on_room_redaction);
+        match registration {
+            Some(registration) => {
+                log_message(
+                    Matrix,
+                    &format!(
+                        "Listening for appservice transactions on {}...",
+                        bot_configuration.appservice_listen_address
+                    ),
+                );
+                appservice::serve_transactions(client, bot_configuration, registration.hs_token)
+                    .await
+            }
+            None => {
+                let settings = SyncSettings::default().token(client.sync_token().await.unwrap());
+                match client.sync(settings).await {
+                    Ok(_) => Ok(()),
+                    Err(error) => Err(error.to_string()),
+                }
+            }
+        }
+    });
 
-    Ok(())
+    let mut result = Ok(());
+    tokio::select! {
+        _ = wait_for_shutdown_signal() => (),
+        matrix_result = &mut matrix_receiver => {
+            if let Ok(Err(error)) = matrix_result {
+                log_message(MatrixError, &format!("Unable to receive events: {error}"));
+                result = Err(anyhow::anyhow!(error));
+            }
+        }
+    }
+    log_message(
+        Bot,
+        "Shutting down, waiting for queued messages to be bridged...",
+    );
+    // Unacknowledged appservice transactions are retried by the
+    // homeserver, and an interrupted sync is simply repeated next time.
+    matrix_receiver.abort();
+    let _ = shutdown_sender.send(true);
+    for thread in [netchat_receiver, netchat_bridge, matrix_bridge] {
+        if let Err(error) = thread.await {
+            log_error(error);
+        }
+    }
+    if let Err(error) = database.flush().await {
+        log_message(Error, &format!("Unable to flush database: {error}"));
+    }
+    // The session is kept (not logged out) so that it can be restored
+    // on the next start, along with the device's encryption keys.
+    log_message(Bot, "Successfully shut down!");
+
+    result
+}
+
+async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+                Ok(terminate) => terminate,
+                Err(error) => {
+                    log_error(error);
+                    let _ = tokio::signal::ctrl_c().await;
+                    return;
+                }
+            };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
 
 async fn on_stripped_state_member(
@@ -485,150 +1527,819 @@ async fn on_room_message(
     room: Room,
     matrix_context: Ctx<MatrixContext>,
 ) {
-    if event.sender == room.client().user_id().unwrap() {
+    if event.sender == room.client().user_id().unwrap()
+        || appservice::is_ghost(&matrix_context.bot_configuration, &event.sender)
+    {
         return;
     }
 
     if let Room::Joined(room) = room {
-        match event.content.msgtype {
-            MessageType::Text(_) => {
-                let body = event.content.body();
-                if body.starts_with(&matrix_context.bot_configuration.command_prefix) {
-                    let mut characters = body.chars();
-                    characters.next();
-                    let command = characters.as_str().split(" ").nth(0).unwrap();
-                    let mut arguments = Vec::new();
-                    let mut current_argument = String::new();
-                    let mut in_string = (false, "");
-                    for letter in characters {
-                        if letter == '\\' {
-                            in_string = (true, "\\");
-                            continue;
-                        }
-                        if letter == ' ' && !in_string.0 {
-                            if current_argument.len() > 0 {
-                                arguments.push(current_argument);
-                                current_argument = String::new();
-                            }
-                            continue;
+        if let MessageType::Text(_) = event.content.msgtype {
+            let body = event.content.body();
+            if body.starts_with(&matrix_context.bot_configuration.command_prefix) {
+                let mut characters = body.chars();
+                characters.next();
+                let command = characters.as_str().split(' ').next().unwrap();
+                let mut arguments = Vec::new();
+                let mut current_argument = String::new();
+                let mut in_string = (false, "");
+                for letter in characters {
+                    if letter == '\\' {
+                        in_string = (true, "\\");
+                        continue;
+                    }
+                    if letter == ' ' && !in_string.0 {
+                        if !current_argument.is_empty() {
+                            arguments.push(current_argument);
+                            current_argument = String::new();
                         }
-                        if letter == '"' && !in_string.0 {
-                            in_string = (true, "\"");
-                            if current_argument.len() > 0 {
-                                arguments.push(current_argument);
-                                current_argument = String::new();
-                            }
-                            continue;
-                        } else if letter == '"' && in_string.0 {
-                            in_string = (false, "");
-                            if current_argument.len() > 0 {
-                                arguments.push(current_argument);
-                                current_argument = String::new();
-                            }
-                            continue;
+                        continue;
+                    }
+                    if letter == '"' && !in_string.0 {
+                        in_string = (true, "\"");
+                        if !current_argument.is_empty() {
+                            arguments.push(current_argument);
+                            current_argument = String::new();
                         }
-                        current_argument.push(letter);
-                        if in_string == (true, "\\") {
-                            in_string = (false, "");
+                        continue;
+                    } else if letter == '"' && in_string.0 {
+                        in_string = (false, "");
+                        if !current_argument.is_empty() {
+                            arguments.push(current_argument);
+                            current_argument = String::new();
                         }
+                        continue;
                     }
-                    if current_argument.len() > 0 {
-                        arguments.push(current_argument);
+                    current_argument.push(letter);
+                    if in_string == (true, "\\") {
+                        in_string = (false, "");
                     }
-                    arguments.remove(0);
+                }
+                if !current_argument.is_empty() {
+                    arguments.push(current_argument);
+                }
+                arguments.remove(0);
+                let (arguments, options) = commands::split_options(command, arguments);
 
-                    let command_input = commands::CommandInput {
-                        event: event.clone(),
-                        room,
-                        matrix_context,
-                        arguments,
-                    };
-                    match command {
-                        "ping" => commands::basic::ping_command(&command_input).await,
-                        "bridge" => commands::bridge::bridge_command(&command_input).await,
-                        "username" => commands::username::username_command(&command_input).await,
-                        _ => (),
-                    };
-                } else {
-                    match matrix_context
-                        .database
-                        .get(&format!("bridge.{}", room.room_id().as_str()))
-                    {
-                        Ok(value) => match value {
-                            Some(value) => {
-                                match serde_json::from_str::<BridgedRoomData>(value.as_str()) {
-                                    Ok(bridged_room_data) => {
-                                        let mut custom_username = false;
-                                        let mut netchat_username =
-                                            match matrix_context.database.get(&format!(
-                                                "username.{}.{}",
-                                                room.room_id().as_str(),
-                                                event.sender.as_str()
-                                            )) {
-                                                Ok(netchat_username) => match netchat_username {
-                                                    Some(netchat_username) => {
-                                                        custom_username = true;
-                                                        netchat_username
-                                                    }
-                                                    None => "".to_string(),
-                                                },
-                                                Err(error) => {
-                                                    log_error(error);
-                                                    "".to_string()
-                                                }
-                                            };
-                                        if !custom_username {
-                                            netchat_username = match room
-                                                .get_member(&event.sender)
-                                                .await
-                                            {
-                                                Ok(member) => match member {
-                                                    Some(member) => match member.display_name() {
-                                                        Some(display_name) => {
-                                                            display_name.to_string()
-                                                        }
-                                                        None => event.sender.as_str().to_string(),
-                                                    },
-                                                    None => event.sender.as_str().to_string(),
-                                                },
-                                                Err(error) => {
-                                                    log_error(error);
-                                                    event.sender.as_str().to_string()
-                                                }
-                                            }
-                                        };
-                                        matrix_context
-                                            .matrix_queue_sender
-                                            .lock()
-                                            .unwrap()
-                                            .send(MatrixBridgeMessage {
-                                                netchat_room_name: bridged_room_data
-                                                    .clone()
-                                                    .room_name,
-                                                netchat_room_password: bridged_room_data
-                                                    .clone()
-                                                    .room_password,
-                                                netchat_username: netchat_username.to_string(),
-                                                netchat_message: body.to_string(),
-                                                matrix_room_id: room.room_id().as_str().to_string(),
-                                            })
-                                            .unwrap();
-                                        log_matrix_error(room.read_receipt(&event.event_id).await);
-                                    }
-                                    Err(error) => {
-                                        log_error(&error);
-                                    }
-                                }
-                            }
-                            None => (),
-                        },
-                        Err(error) => {
-                            log_error(&error);
-                        }
-                    }
+                let command_input = commands::CommandInput {
+                    event: event.clone(),
+                    room,
+                    matrix_context,
+                    arguments,
+                    options,
+                };
+                match command {
+                    "ping" => commands::basic::ping_command(&command_input).await,
+                    "bridge" => commands::bridge::bridge_command(&command_input).await,
+                    "username" => commands::username::username_command(&command_input).await,
+                    _ => (),
+                };
+                return;
+            }
+        }
+
+        let bridged_room_data =
+            match get_bridged_room_data(&matrix_context.bridges, room.room_id().as_str()) {
+                Some(bridged_room_data) => bridged_room_data,
+                None => return,
+            };
+        let netchat_username = get_netchat_username(&room, &event.sender, &matrix_context).await;
+        if let Some(Relation::Replacement(replacement)) = &event.content.relates_to {
+            // Only edits of messages that made it (or are still on their way)
+            // to NetChat are relayed, so the "* fixed text" fallback never
+            // shows up there.
+            let room_id = room.room_id().as_str();
+            let edited_event_id = replacement.event_id.as_str();
+            match matrix_context
+                .relayed
+                .contains(room_id, edited_event_id)
+                .and_then(|is_relayed| {
+                    Ok(is_relayed
+                        || matrix_context
+                            .outbound
+                            .contains_event(room_id, edited_event_id)?)
+                }) {
+                Ok(true) => (),
+                Ok(false) => return,
+                Err(error) => {
+                    log_error(error);
+                    return;
+                }
+            }
+            let netchat_message = match format_netchat_message(
+                &room,
+                &replacement.new_content.msgtype,
+                &netchat_username,
+                &bridged_room_data,
+                false,
+            )
+            .await
+            {
+                Some(netchat_message) => netchat_message,
+                None => return,
+            };
+            queue_netchat_message(
+                &matrix_context,
+                room.room_id(),
+                &event.sender,
+                format!("{netchat_username} (edited)"),
+                netchat_message,
+                None,
+                Some(&replacement.event_id),
+            );
+        } else {
+            let in_reply_to = match &event.content.relates_to {
+                Some(Relation::Reply { in_reply_to }) => Some(&in_reply_to.event_id),
+                _ => None,
+            };
+            let mut netchat_message = match format_netchat_message(
+                &room,
+                &event.content.msgtype,
+                &netchat_username,
+                &bridged_room_data,
+                in_reply_to.is_some(),
+            )
+            .await
+            {
+                Some(netchat_message) => netchat_message,
+                None => return,
+            };
+            if let Some(event_id) = in_reply_to {
+                if let Some((name, text)) =
+                    get_replied_message(&room, event_id, &matrix_context).await
+                {
+                    netchat_message = format!(
+                        "{}\n{netchat_message}",
+                        utilities::format_reply_quote(&name, &text)
+                    );
+                }
+            }
+            queue_netchat_message(
+                &matrix_context,
+                room.room_id(),
+                &event.sender,
+                netchat_username,
+                netchat_message,
+                Some(&event.event_id),
+                None,
+            );
+        }
+        log_matrix_error(room.read_receipt(&event.event_id).await);
+    }
+}
+
+async fn on_room_redaction(
+    event: OriginalSyncRoomRedactionEvent,
+    room: Room,
+    matrix_context: Ctx<MatrixContext>,
+) {
+    if event.sender == room.client().user_id().unwrap()
+        || appservice::is_ghost(&matrix_context.bot_configuration, &event.sender)
+    {
+        return;
+    }
+
+    if let Room::Joined(room) = room {
+        let bridged_room_data =
+            match get_bridged_room_data(&matrix_context.bridges, room.room_id().as_str()) {
+                Some(bridged_room_data) => bridged_room_data,
+                None => return,
+            };
+        if !bridged_room_data.relay_redactions {
+            return;
+        }
+        match matrix_context
+            .relayed
+            .contains(room.room_id().as_str(), event.redacts.as_str())
+        {
+            Ok(true) => (),
+            Ok(false) => return,
+            Err(error) => {
+                log_error(error);
+                return;
+            }
+        }
+        if let Err(error) = matrix_context
+            .relayed
+            .remove(room.room_id().as_str(), event.redacts.as_str())
+        {
+            log_error(error);
+        }
+        let netchat_username = get_netchat_username(&room, &event.sender, &matrix_context).await;
+        queue_netchat_message(
+            &matrix_context,
+            room.room_id(),
+            &event.sender,
+            netchat_username.to_string(),
+            format!("* {netchat_username} deleted a message"),
+            None,
+            None,
+        );
+    }
+}
+
+async fn format_netchat_message(
+    room: &room::Joined,
+    message_type: &MessageType,
+    netchat_username: &str,
+    bridged_room_data: &BridgedRoomData,
+    is_reply: bool,
+) -> Option<String> {
+    let text_body = |body: &str| {
+        if is_reply {
+            utilities::strip_reply_fallback(body).to_string()
+        } else {
+            body.to_string()
+        }
+    };
+    Some(match message_type {
+        MessageType::Text(content) => text_body(&content.body),
+        MessageType::Emote(content) => {
+            format!("* {netchat_username} {}", text_body(&content.body))
+        }
+        MessageType::Notice(content) => {
+            if !bridged_room_data.relay_notices {
+                return None;
+            }
+            format!("[notice] {}", text_body(&content.body))
+        }
+        MessageType::Image(content) => utilities::describe_attachment(
+            "image",
+            &content.body,
+            &content.source,
+            content.info.as_ref().and_then(|info| info.size),
+            &room.client().homeserver().await,
+        ),
+        MessageType::File(content) => utilities::describe_attachment(
+            "file",
+            content.filename.as_ref().unwrap_or(&content.body),
+            &content.source,
+            content.info.as_ref().and_then(|info| info.size),
+            &room.client().homeserver().await,
+        ),
+        MessageType::Audio(content) => utilities::describe_attachment(
+            "audio",
+            &content.body,
+            &content.source,
+            content.info.as_ref().and_then(|info| info.size),
+            &room.client().homeserver().await,
+        ),
+        MessageType::Video(content) => utilities::describe_attachment(
+            "video",
+            &content.body,
+            &content.source,
+            content.info.as_ref().and_then(|info| info.size),
+            &room.client().homeserver().await,
+        ),
+        _ => return None,
+    })
+}
+
+/// Looks up who sent the message being replied to and what it said.
+/// Messages the bot relayed from NetChat are attributed to their NetChat sender.
+async fn get_replied_message(
+    room: &room::Joined,
+    event_id: &EventId,
+    matrix_context: &MatrixContext,
+) -> Option<(String, String)> {
+    let timeline_event = match room.event(event_id).await {
+        Ok(timeline_event) => timeline_event,
+        Err(error) => {
+            log_error(error);
+            return None;
+        }
+    };
+    let original_event = match timeline_event.event.deserialize() {
+        Ok(AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(
+            MessageLikeEvent::Original(original_event),
+        ))) => original_event,
+        Ok(_) => return None,
+        Err(error) => {
+            log_error(error);
+            return None;
+        }
+    };
+    let body = utilities::strip_reply_fallback(original_event.content.body()).to_string();
+    if Some(original_event.sender.as_ref()) == room.client().user_id() {
+        if let NetChatMessage::Chat { username, body, .. } =
+            NetChatMessage::parse(&body.replace("<b>", "").replace("</b>", ""))
+        {
+            return Some((username, body));
+        }
+    }
+    let name = get_netchat_username(room, &original_event.sender, matrix_context).await;
+    Some((name, body))
+}
+
+fn queue_netchat_message(
+    matrix_context: &MatrixContext,
+    room_id: &RoomId,
+    sender: &UserId,
+    netchat_username: String,
+    netchat_message: String,
+    matrix_event_id: Option<&EventId>,
+    edited_event_id: Option<&EventId>,
+) {
+    if let Err(error) = matrix_context.outbound.push(&MatrixBridgeMessage {
+        matrix_room_id: room_id.as_str().to_string(),
+        matrix_sender: sender.as_str().to_string(),
+        netchat_username,
+        netchat_message,
+        attempts: 0,
+        next_attempt_at: 0,
+        matrix_event_id: matrix_event_id.map(|event_id| event_id.as_str().to_string()),
+        edited_event_id: edited_event_id.map(|event_id| event_id.as_str().to_string()),
+    }) {
+        log_error(error);
+        return;
+    }
+    // a full queue already means that a wakeup is pending
+    let _ = matrix_context.matrix_queue_sender.try_send(());
+}
+
+fn get_bridged_room_data(bridges: &BridgeStore, matrix_room_id: &str) -> Option<BridgedRoomData> {
+    match bridges.get(matrix_room_id) {
+        Ok(bridged_room_data) => bridged_room_data,
+        Err(error) => {
+            log_error(&error);
+            None
+        }
+    }
+}
+
+async fn get_netchat_username(
+    room: &room::Joined,
+    user_id: &UserId,
+    matrix_context: &MatrixContext,
+) -> String {
+    match matrix_context
+        .usernames
+        .get(room.room_id().as_str(), user_id.as_str())
+    {
+        Ok(Some(netchat_username)) => netchat_username,
+        Ok(None) => get_display_name(room, user_id).await,
+        Err(error) => {
+            log_error(error);
+            get_display_name(room, user_id).await
+        }
+    }
+}
+
+async fn get_display_name(room: &room::Joined, user_id: &UserId) -> String {
+    match room.get_member(user_id).await {
+        Ok(Some(member)) => match member.display_name() {
+            Some(display_name) => display_name.to_string(),
+            None => user_id.as_str().to_string(),
+        },
+        Ok(None) => user_id.as_str().to_string(),
+        Err(error) => {
+            log_error(error);
+            user_id.as_str().to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_netchat::{MockFailure, MockNetChat};
+
+    struct PollHarness {
+        mock: MockNetChat,
+        database: Database,
+        receiver: mpsc::Receiver<NetChatBridgeMessage>,
+        poller: tokio::task::JoinHandle<()>,
+        shutdown: watch::Sender<bool>,
+    }
+
+    impl PollHarness {
+        async fn start(bridged_room_data: BridgedRoomData, mock: MockNetChat) -> Self {
+            Self::start_with(vec![("!room:example.org", bridged_room_data)], mock).await
+        }
+
+        async fn start_with(bridges: Vec<(&str, BridgedRoomData)>, mock: MockNetChat) -> Self {
+            let bot_configuration = Configuration {
+                refresh_interval: 1,
+                instance_url: mock.url.to_string(),
+                ..Configuration::default()
+            };
+            let database = Database::temporary();
+            for (matrix_room_id, bridged_room_data) in bridges {
+                database
+                    .set(
+                        &format!("bridge.{matrix_room_id}"),
+                        &serde_json::to_string(&bridged_room_data).unwrap(),
+                    )
+                    .unwrap();
+            }
+            let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+            let (shutdown, shutdown_receiver) = watch::channel(false);
+            let netchat_client = NetChatClient::new(&bot_configuration).unwrap();
+            let bridges = BridgeStore::new(&database, None);
+            let poller = tokio::spawn(async move {
+                receive_netchat_messages(
+                    sender,
+                    &bot_configuration,
+                    netchat_client,
+                    bridges,
+                    shutdown_receiver,
+                )
+                .await
+            });
+            Self {
+                mock,
+                database,
+                receiver,
+                poller,
+                shutdown,
+            }
+        }
+
+        async fn next_message(&mut self) -> Option<NetChatBridgeMessage> {
+            for _ in 0..50 {
+                if let Ok(bridge_message) = self.receiver.try_recv() {
+                    return Some(bridge_message);
                 }
+                sleep(Duration::from_millis(100)).await;
+            }
+            None
+        }
+
+        fn bridged_room_data(&self) -> BridgedRoomData {
+            serde_json::from_str(
+                &self
+                    .database
+                    .get("bridge.!room:example.org")
+                    .unwrap()
+                    .unwrap(),
+            )
+            .unwrap()
+        }
+    }
+
+    impl Drop for PollHarness {
+        fn drop(&mut self) {
+            self.poller.abort();
+        }
+    }
+
+    fn bridged_room_data(message_count: usize) -> BridgedRoomData {
+        BridgedRoomData {
+            room_name: "room".to_string(),
+            room_password: "password".to_string(),
+            message_count,
+            instance_url: None,
+            sent_messages: Vec::new(),
+            relay_notices: false,
+            relay_redactions: false,
+            poll_interval: None,
+            last_activity: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    fn username_of(bridge_message: &NetChatBridgeMessage) -> &str {
+        match &bridge_message.message {
+            NetChatMessage::Chat { username, .. } => username,
+            NetChatMessage::Unknown(raw_message) => panic!("unparsed message {raw_message}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn polls_only_new_messages() {
+        let mock = MockNetChat::start().await;
+        mock.add_room("room", "password");
+        mock.push_message("room", "old", "already bridged");
+        let mut harness = PollHarness::start(bridged_room_data(1), mock).await;
+
+        harness.mock.push_message("room", "alice", "hello");
+        let bridge_message = harness.next_message().await.unwrap();
+        assert_eq!(bridge_message.matrix_room_id, "!room:example.org");
+        assert_eq!(username_of(&bridge_message), "alice");
+        assert_eq!(harness.bridged_room_data().message_count, 2);
+    }
+
+    #[tokio::test]
+    async fn skips_echoes_of_sent_messages() {
+        let mock = MockNetChat::start().await;
+        mock.add_room("room", "password");
+        let mut data = bridged_room_data(0);
+        data.sent_messages.push(SentMessage {
+            username: "bob".to_string(),
+            body: "from matrix".to_string(),
+            sent_at: chrono::Utc::now().timestamp(),
+            message_index: 0,
+        });
+        mock.push_message("room", "bob", "from matrix");
+        mock.push_message("room", "bob", "from netchat");
+        let mut harness = PollHarness::start(data, mock).await;
+
+        let bridge_message = harness.next_message().await.unwrap();
+        match bridge_message.message {
+            NetChatMessage::Chat { body, .. } => assert_eq!(body, "from netchat"),
+            NetChatMessage::Unknown(raw_message) => panic!("unparsed message {raw_message}"),
+        }
+        assert!(harness.bridged_room_data().sent_messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn relays_identical_messages_sent_before_the_echo() {
+        let mock = MockNetChat::start().await;
+        mock.add_room("room", "password");
+        let mut data = bridged_room_data(0);
+        data.sent_messages.push(SentMessage {
+            username: "bob".to_string(),
+            body: "hello".to_string(),
+            sent_at: chrono::Utc::now().timestamp(),
+            message_index: 1,
+        });
+        // another "bob" on NetChat said the same thing just before the bridge did
+        mock.push_message("room", "bob", "hello");
+        mock.push_message("room", "bob", "hello");
+        mock.push_message("room", "carol", "hi");
+        let mut harness = PollHarness::start(data, mock).await;
+
+        assert_eq!(username_of(&harness.next_message().await.unwrap()), "bob");
+        assert_eq!(username_of(&harness.next_message().await.unwrap()), "carol");
+        harness.mock.push_message("room", "alice", "after");
+        assert_eq!(username_of(&harness.next_message().await.unwrap()), "alice");
+        assert!(harness.bridged_room_data().sent_messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn counts_messages_posted_while_polling() {
+        let mock = MockNetChat::start().await;
+        mock.add_room("room", "password");
+        mock.push_message("room", "alice", "first");
+        mock.push_message_after_count("room", "bob", "second");
+        let mut harness = PollHarness::start(bridged_room_data(0), mock).await;
+
+        assert_eq!(username_of(&harness.next_message().await.unwrap()), "alice");
+        assert_eq!(username_of(&harness.next_message().await.unwrap()), "bob");
+        harness.mock.push_message("room", "carol", "third");
+        assert_eq!(username_of(&harness.next_message().await.unwrap()), "carol");
+        assert_eq!(harness.bridged_room_data().message_count, 3);
+    }
+
+    #[tokio::test]
+    async fn resets_count_when_room_is_cleared() {
+        let mock = MockNetChat::start().await;
+        mock.add_room("room", "password");
+        let mut harness = PollHarness::start(bridged_room_data(5), mock).await;
+
+        let requests = harness.mock.request_count();
+        while harness.mock.request_count() == requests {
+            sleep(Duration::from_millis(50)).await;
+        }
+        harness.mock.push_message("room", "alice", "after clearing");
+        let bridge_message = harness.next_message().await.unwrap();
+        assert_eq!(username_of(&bridge_message), "alice");
+    }
+
+    #[tokio::test]
+    async fn stops_polling_on_shutdown() {
+        let mock = MockNetChat::start().await;
+        mock.add_room("room", "password");
+        mock.push_message("room", "alice", "before shutdown");
+        let mut harness = PollHarness::start(bridged_room_data(0), mock).await;
+
+        assert!(harness.next_message().await.is_some());
+        harness.shutdown.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), &mut harness.poller)
+            .await
+            .unwrap()
+            .unwrap();
+        // the queue closes once the poller is gone
+        assert!(harness.receiver.recv().await.is_none());
+    }
+
+    struct OutboundHarness {
+        mock: MockNetChat,
+        bot_configuration: Configuration,
+        netchat_client: NetChatClient,
+        bridges: BridgeStore,
+        outbound: OutboundStore,
+        relayed: RelayedStore,
+    }
+
+    impl OutboundHarness {
+        async fn start(room_password: &str) -> Self {
+            let mock = MockNetChat::start().await;
+            mock.add_room("room", room_password);
+            let bot_configuration = Configuration {
+                instance_url: mock.url.to_string(),
+                ..Configuration::default()
+            };
+            let netchat_client = NetChatClient::new(&bot_configuration).unwrap();
+            let database = Database::temporary();
+            let bridges = BridgeStore::new(&database, None);
+            bridges
+                .set("!room:example.org", &bridged_room_data(0))
+                .unwrap();
+            Self {
+                mock,
+                bot_configuration,
+                netchat_client,
+                bridges,
+                outbound: OutboundStore::new(&database),
+                relayed: RelayedStore::new(&database),
             }
-            _ => (),
         }
+
+        fn queue(&self, body: &str) {
+            self.outbound
+                .push(&MatrixBridgeMessage {
+                    matrix_room_id: "!room:example.org".to_string(),
+                    matrix_sender: "@alice:example.org".to_string(),
+                    netchat_username: "alice".to_string(),
+                    netchat_message: body.to_string(),
+                    attempts: 0,
+                    next_attempt_at: 0,
+                    matrix_event_id: Some(format!("${body}")),
+                    edited_event_id: None,
+                })
+                .unwrap();
+        }
+
+        fn queue_edit(&self, edited_body: &str, body: &str) {
+            self.outbound
+                .push(&MatrixBridgeMessage {
+                    matrix_room_id: "!room:example.org".to_string(),
+                    matrix_sender: "@alice:example.org".to_string(),
+                    netchat_username: "alice (edited)".to_string(),
+                    netchat_message: body.to_string(),
+                    attempts: 0,
+                    next_attempt_at: 0,
+                    matrix_event_id: None,
+                    edited_event_id: Some(format!("${edited_body}")),
+                })
+                .unwrap();
+        }
+
+        async fn send(&self) -> (Vec<(MatrixBridgeMessage, NetChatError)>, Option<i64>) {
+            send_outbound_messages(
+                &self.bot_configuration,
+                &self.netchat_client,
+                &self.outbound,
+                &self.relayed,
+                &self.bridges,
+            )
+            .await
+        }
+
+        fn is_relayed(&self, body: &str) -> bool {
+            self.relayed
+                .contains("!room:example.org", &format!("${body}"))
+                .unwrap()
+        }
+
+        fn queued(&self) -> Vec<(String, MatrixBridgeMessage)> {
+            self.outbound
+                .iter()
+                .map(|(key, bridge_message)| (key, bridge_message.unwrap()))
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_outbound_messages_in_order() {
+        let mut harness = OutboundHarness::start("password").await;
+        harness.queue("first");
+        harness.queue("second");
+        // edited while the original is still waiting for a retry
+        harness.queue_edit("first", "first!");
+
+        harness.mock.fail_with(Some(MockFailure::ServerError));
+        let (failed_messages, next_attempt_at) = harness.send().await;
+        assert!(failed_messages.is_empty());
+        assert!(next_attempt_at.is_some());
+        let queued = harness.queued();
+        assert_eq!(
+            queued
+                .iter()
+                .map(|(_, bridge_message)| bridge_message.attempts)
+                .collect::<Vec<_>>(),
+            [1, 0, 0]
+        );
+        // nothing reached NetChat, so there is no echo to wait for
+        let bridged_room_data = harness.bridges.get("!room:example.org").unwrap().unwrap();
+        assert!(bridged_room_data.sent_messages.is_empty());
+        assert!(!harness.is_relayed("first"));
+
+        let (key, mut bridge_message) = queued.into_iter().next().unwrap();
+        bridge_message.next_attempt_at = 0;
+        harness.outbound.set(&key, &bridge_message).unwrap();
+        harness.mock.fail_with(None);
+        // a new client has no backoff left from the server error
+        harness.netchat_client = NetChatClient::new(&harness.bot_configuration).unwrap();
+        assert!(harness.send().await.0.is_empty());
+        let bodies: Vec<String> = harness
+            .mock
+            .messages("room")
+            .iter()
+            .map(|raw_message| match NetChatMessage::parse(raw_message) {
+                NetChatMessage::Chat { body, .. } => body,
+                NetChatMessage::Unknown(raw_message) => panic!("unparsed message {raw_message}"),
+            })
+            .collect();
+        assert_eq!(bodies, ["first", "second", "first!"]);
+        assert!(harness.queued().is_empty());
+        assert!(harness.is_relayed("first") && harness.is_relayed("second"));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_rejected_messages() {
+        let harness = OutboundHarness::start("changed").await;
+        harness.queue("hello");
+        harness.queue_edit("hello", "hello!");
+
+        let (failed_messages, next_attempt_at) = harness.send().await;
+        assert_eq!(failed_messages.len(), 1);
+        assert!(matches!(failed_messages[0].1, NetChatError::Unauthorized));
+        assert_eq!(next_attempt_at, None);
+        assert!(harness.queued().is_empty());
+        // edits of a message that never arrived aren't relayed either
+        assert!(!harness.is_relayed("hello"));
+    }
+
+    #[test]
+    fn slows_down_polling_of_quiet_rooms() {
+        let bot_configuration = Configuration {
+            refresh_interval: 5,
+            max_refresh_interval: 120,
+            ..Configuration::default()
+        };
+        let mut data = bridged_room_data(0);
+        assert_eq!(
+            data.poll_interval(&bot_configuration),
+            Duration::from_secs(5)
+        );
+        data.last_activity -= 600;
+        assert_eq!(
+            data.poll_interval(&bot_configuration),
+            Duration::from_secs(60)
+        );
+        data.last_activity = 0;
+        assert_eq!(
+            data.poll_interval(&bot_configuration),
+            Duration::from_secs(120)
+        );
+        data.poll_interval = Some(30);
+        assert_eq!(
+            data.poll_interval(&bot_configuration),
+            Duration::from_secs(30)
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_rooms_dont_hold_up_others() {
+        let mock = MockNetChat::start().await;
+        mock.add_room("room", "password");
+        mock.add_room("slow", "password");
+        mock.delay_room("slow", Duration::from_secs(30));
+        let mut harness = PollHarness::start(bridged_room_data(0), mock).await;
+        let mut slow_room_data = bridged_room_data(0);
+        slow_room_data.room_name = "slow".to_string();
+        harness
+            .database
+            .set(
+                "bridge.!slow:example.org",
+                &serde_json::to_string(&slow_room_data).unwrap(),
+            )
+            .unwrap();
+
+        // give the poller a cycle to get stuck on the slow room
+        sleep(Duration::from_secs(2)).await;
+        harness.mock.push_message("room", "alice", "hello");
+        let bridge_message = harness.next_message().await.unwrap();
+        assert_eq!(bridge_message.matrix_room_id, "!room:example.org");
+    }
+
+    #[tokio::test]
+    async fn shares_polls_between_bridges_of_one_room() {
+        let mock = MockNetChat::start().await;
+        mock.add_room("room", "password");
+        let mut sender_data = bridged_room_data(0);
+        sender_data.sent_messages.push(SentMessage {
+            username: "bob".to_string(),
+            body: "from matrix".to_string(),
+            sent_at: chrono::Utc::now().timestamp(),
+            message_index: 0,
+        });
+        mock.push_message("room", "bob", "from matrix");
+        let mut harness = PollHarness::start_with(
+            vec![
+                ("!room:example.org", sender_data),
+                ("!other:example.org", bridged_room_data(0)),
+            ],
+            mock,
+        )
+        .await;
+
+        // only the Matrix room that sent the message skips its echo
+        let bridge_message = harness.next_message().await.unwrap();
+        assert_eq!(bridge_message.matrix_room_id, "!other:example.org");
+        assert_eq!(username_of(&bridge_message), "bob");
+        // a single messageCount and rawMessages request served both bridges
+        assert_eq!(harness.mock.request_count(), 2);
     }
 }
@@ -0,0 +1,158 @@
+//! Upgrades of the records stored under `bridge.{room_id}`. The version
+//! the database is at is kept under `schema_version` (missing means 0),
+//! and every migration newer than that is applied once at startup.
+
+use crate::database::Database;
+use serde_json::{Map, Value};
+
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+pub struct Migration {
+    pub version: u64,
+    pub description: &'static str,
+    migrate: fn(&mut Map<String, Value>),
+}
+
+/// Ordered by version, which always has to be the previous version plus one.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "remove the unused pending_messages counter",
+        migrate: |record| {
+            record.remove("pending_messages");
+        },
+    },
+    Migration {
+        version: 2,
+        description: "start the idle time of existing bridges now",
+        migrate: |record| {
+            if !matches!(record.get("last_activity"), Some(Value::Number(_))) {
+                record.insert(
+                    "last_activity".to_string(),
+                    chrono::Utc::now().timestamp().into(),
+                );
+            }
+        },
+    },
+];
+
+pub fn current_version() -> u64 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+pub struct MigrationReport {
+    pub from_version: u64,
+    pub to_version: u64,
+    /// The keys of all changed records, with the migrations that changed them.
+    pub changes: Vec<(String, Vec<&'static str>)>,
+}
+
+/// Brings every bridge record up to the current schema. With `dry_run`,
+/// nothing is written and the report only shows what would change.
+pub fn migrate(database: &Database, dry_run: bool) -> Result<MigrationReport, String> {
+    let from_version = match database.get(SCHEMA_VERSION_KEY)? {
+        Some(version) => match version.parse::<u64>() {
+            Ok(version) => version,
+            Err(error) => return Err(format!("invalid schema version {version:?}: {error}")),
+        },
+        None => 0,
+    };
+    let to_version = current_version();
+    if from_version > to_version {
+        return Err(format!(
+            "database has schema version {from_version}, but this build only supports up to {to_version}"
+        ));
+    }
+
+    let mut changes = Vec::new();
+    for (key, value) in database.scan_prefix("bridge.") {
+        let mut record = match serde_json::from_str::<Map<String, Value>>(&value) {
+            Ok(record) => record,
+            Err(error) => return Err(format!("unable to parse {key}: {error}")),
+        };
+        let mut applied = Vec::new();
+        for migration in MIGRATIONS
+            .iter()
+            .filter(|migration| migration.version > from_version)
+        {
+            let previous = record.clone();
+            (migration.migrate)(&mut record);
+            if record != previous {
+                applied.push(migration.description);
+            }
+        }
+        if applied.is_empty() {
+            continue;
+        }
+        if !dry_run {
+            database.set(&key, &Value::Object(record).to_string())?;
+        }
+        changes.push((key, applied));
+    }
+    if !dry_run && from_version != to_version {
+        database.set(SCHEMA_VERSION_KEY, &to_version.to_string())?;
+    }
+    Ok(MigrationReport {
+        from_version,
+        to_version,
+        changes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD_RECORD: &str =
+        r#"{"room_name":"lobby","room_password":"hunter2","message_count":3,"pending_messages":1}"#;
+
+    #[test]
+    fn migrates_old_records() {
+        let database = Database::temporary();
+        database.set("bridge.!a:example.org", OLD_RECORD).unwrap();
+
+        let report = migrate(&database, false).unwrap();
+        assert_eq!(
+            (report.from_version, report.to_version),
+            (0, current_version())
+        );
+        assert_eq!(report.changes.len(), 1);
+        assert_eq!(report.changes[0].1.len(), 2);
+
+        let record: Map<String, Value> =
+            serde_json::from_str(&database.get("bridge.!a:example.org").unwrap().unwrap()).unwrap();
+        assert!(!record.contains_key("pending_messages"));
+        assert!(record["last_activity"].as_i64().unwrap() > 0);
+        assert_eq!(record["room_password"], "hunter2");
+        assert_eq!(
+            database.get(SCHEMA_VERSION_KEY).unwrap(),
+            Some(current_version().to_string())
+        );
+
+        // everything is up to date now
+        assert!(migrate(&database, false).unwrap().changes.is_empty());
+    }
+
+    #[test]
+    fn dry_run_changes_nothing() {
+        let database = Database::temporary();
+        database.set("bridge.!a:example.org", OLD_RECORD).unwrap();
+
+        let report = migrate(&database, true).unwrap();
+        assert_eq!(report.changes.len(), 1);
+        assert_eq!(
+            database.get("bridge.!a:example.org").unwrap().as_deref(),
+            Some(OLD_RECORD)
+        );
+        assert_eq!(database.get(SCHEMA_VERSION_KEY).unwrap(), None);
+    }
+
+    #[test]
+    fn refuses_newer_databases() {
+        let database = Database::temporary();
+        database
+            .set(SCHEMA_VERSION_KEY, &(current_version() + 1).to_string())
+            .unwrap();
+        assert!(migrate(&database, false).is_err());
+    }
+}
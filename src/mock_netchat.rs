@@ -0,0 +1,204 @@
+//! A small in-process imitation of a NetChat instance, used by the tests
+//! to exercise the HTTP client and the poll loop without the public server.
+
+use crate::utilities::percent_decode;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const SUBSTITUTIONS: [(&str, &str); 7] = [
+    ("#", "||HAS||"),
+    ("%", "||PER||"),
+    ("&", "||AMP||"),
+    ("/", "||SLA||"),
+    ("?", "||QUE||"),
+    ("\\", "||RSLA||"),
+    ("\n", "||NEWL||"),
+];
+
+#[derive(Clone, Copy)]
+pub enum MockFailure {
+    Unauthorized,
+    RateLimited { retry_after: Option<u64> },
+    ServerError,
+}
+
+#[derive(Default)]
+struct MockState {
+    /// Rooms keyed by name, holding their password and raw messages.
+    rooms: HashMap<String, (String, Vec<String>)>,
+    failure: Option<MockFailure>,
+    requests: usize,
+    /// Rooms whose requests only get a response after a while.
+    delays: HashMap<String, Duration>,
+    /// Messages that are posted right after the room's next messageCount request.
+    late_messages: HashMap<String, Vec<String>>,
+}
+
+pub struct MockNetChat {
+    pub url: String,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockNetChat {
+    pub async fn start() -> Self {
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let state = state.clone();
+                    async move {
+                        let delay = request.uri().path().split('/').nth(2).and_then(|name| {
+                            state
+                                .lock()
+                                .unwrap()
+                                .delays
+                                .get(&percent_decode(name))
+                                .copied()
+                        });
+                        if let Some(delay) = delay {
+                            tokio::time::sleep(delay).await;
+                        }
+                        Ok::<_, Infallible>(handle_request(&state, request))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        Self { url, state }
+    }
+
+    pub fn add_room(&self, name: &str, password: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .rooms
+            .insert(name.to_string(), (password.to_string(), Vec::new()));
+    }
+
+    pub fn push_message(&self, name: &str, username: &str, body: &str) {
+        let mut state = self.state.lock().unwrap();
+        let (_, messages) = state.rooms.get_mut(name).expect("room does not exist");
+        messages.push(format_raw_message(username, body));
+    }
+
+    pub fn messages(&self, name: &str) -> Vec<String> {
+        self.state.lock().unwrap().rooms[name].1.clone()
+    }
+
+    /// Posts a message just after the next messageCount request has been
+    /// answered, as if someone had sent it before rawMessages was fetched.
+    pub fn push_message_after_count(&self, name: &str, username: &str, body: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .late_messages
+            .entry(name.to_string())
+            .or_default()
+            .push(format_raw_message(username, body));
+    }
+
+    /// Makes every following request fail until reset with `None`.
+    pub fn fail_with(&self, failure: Option<MockFailure>) {
+        self.state.lock().unwrap().failure = failure;
+    }
+
+    pub fn delay_room(&self, name: &str, delay: Duration) {
+        self.state
+            .lock()
+            .unwrap()
+            .delays
+            .insert(name.to_string(), delay);
+    }
+
+    pub fn request_count(&self) -> usize {
+        self.state.lock().unwrap().requests
+    }
+}
+
+pub fn format_raw_message(username: &str, body: &str) -> String {
+    format!(
+        "[{}] {username}: {body}",
+        chrono::Utc::now().format("%Y-%m-%d %H:%M:%S")
+    )
+}
+
+fn handle_request(state: &Mutex<MockState>, request: Request<Body>) -> Response<Body> {
+    let mut state = state.lock().unwrap();
+    let state = &mut *state;
+    state.requests += 1;
+    match state.failure {
+        Some(MockFailure::Unauthorized) => return respond(StatusCode::UNAUTHORIZED, ""),
+        Some(MockFailure::ServerError) => return respond(StatusCode::INTERNAL_SERVER_ERROR, ""),
+        Some(MockFailure::RateLimited { retry_after }) => {
+            let mut response = respond(StatusCode::TOO_MANY_REQUESTS, "");
+            if let Some(retry_after) = retry_after {
+                response
+                    .headers_mut()
+                    .insert("Retry-After", retry_after.to_string().parse().unwrap());
+            }
+            return response;
+        }
+        None => (),
+    }
+
+    let segments: Vec<String> = request
+        .uri()
+        .path()
+        .trim_start_matches('/')
+        .split('/')
+        .map(percent_decode)
+        .collect();
+    if segments.len() < 3 {
+        return respond(StatusCode::NOT_FOUND, "");
+    }
+    let (password, name) = (&segments[0], &segments[1]);
+    let messages = match state.rooms.get_mut(name.as_str()) {
+        Some((room_password, messages)) if room_password == password => messages,
+        _ => return respond(StatusCode::UNAUTHORIZED, ""),
+    };
+    match &segments[2..] {
+        [route] if route == "allMessages" => respond(StatusCode::OK, &messages.join("<br>")),
+        [route] if route == "messageCount" => {
+            let count = messages.len();
+            messages.extend(
+                state
+                    .late_messages
+                    .remove(name.as_str())
+                    .unwrap_or_default(),
+            );
+            respond(StatusCode::OK, &count.to_string())
+        }
+        [route] if route == "rawMessages" => {
+            respond(StatusCode::OK, &serde_json::to_string(messages).unwrap())
+        }
+        [_, _, send, username, message] if send == "send" => {
+            messages.push(format_raw_message(&unescape(username), &unescape(message)));
+            respond(StatusCode::OK, "")
+        }
+        _ => respond(StatusCode::NOT_FOUND, ""),
+    }
+}
+
+fn respond(status: StatusCode, body: &str) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response
+}
+
+fn unescape(string: &str) -> String {
+    let mut string = string.to_string();
+    for substitution in SUBSTITUTIONS {
+        string = string.replace(substitution.1, substitution.0);
+    }
+    string
+}
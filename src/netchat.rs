@@ -1,173 +1,568 @@
-use crate::configuration::Configuration;
+use crate::{configuration::Configuration, utilities::escape_html};
+use chrono::NaiveDateTime;
 use once_cell::sync::Lazy;
 use rand::Rng;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-const NETCHAT_INSTANCE: &str = "https://netchat.repl.co";
 static NETCHAT_SESSION_ID: Lazy<u64> = Lazy::new(|| rand::thread_rng().gen());
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+const BACKOFF_BASE: Duration = Duration::from_secs(5);
+const BACKOFF_MAX: Duration = Duration::from_secs(900);
 
-async fn make_request(
-    bot_configuration: &Configuration,
-    url: &str,
-) -> Result<reqwest::Response, reqwest::Error> {
-    let client = reqwest::ClientBuilder::new()
-        .timeout(std::time::Duration::from_secs(
-            bot_configuration.request_timeout,
-        ))
-        .user_agent(format!(
-            "netchat_bridge/{} (id:{}) reqwest",
-            env!("CARGO_PKG_VERSION"),
-            *NETCHAT_SESSION_ID
-        ))
-        .build()
-        .unwrap();
-    client.get(url).send().await
+/// A single entry of a room's `rawMessages`, which NetChat
+/// formats as `[YYYY-MM-DD HH:MM:SS] username: body`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NetChatMessage {
+    Chat {
+        timestamp: NaiveDateTime,
+        username: String,
+        body: String,
+    },
+    /// Anything that doesn't follow the usual format is kept as-is.
+    Unknown(String),
 }
 
-pub async fn get_room(
-    bot_configuration: &Configuration,
-    name: &str,
-    password: &str,
-) -> Result<String, String> {
-    match make_request(
-        &bot_configuration,
-        &format!("{NETCHAT_INSTANCE}/{password}/{name}/allMessages"),
-    )
-    .await
-    {
-        Ok(response) => {
-            if response.status().is_server_error() {
-                Err(format!("encountered server error while fetching room"))
-            } else if response.status() == 429 {
-                Err(format!("encountered ratelimit while fetching room"))
-            } else if response.status() == 401 {
-                Err(format!("unauthorized while fetching room"))
-            } else {
-                match response.text().await {
-                    Ok(text) => Ok(text),
-                    Err(error) => Err(format!(
-                        "failed to get response text while fetching room: {error}"
-                    )),
-                }
-            }
+impl NetChatMessage {
+    pub fn parse(raw_message: &str) -> Self {
+        let unknown = || NetChatMessage::Unknown(raw_message.to_string());
+        let (timestamp, rest) = match raw_message
+            .strip_prefix('[')
+            .and_then(|rest| rest.split_once(']'))
+        {
+            Some(parts) => parts,
+            None => return unknown(),
+        };
+        let timestamp = match NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT) {
+            Ok(timestamp) => timestamp,
+            Err(_) => return unknown(),
+        };
+        match rest.trim_start().split_once(": ") {
+            Some((username, body)) if !username.is_empty() => NetChatMessage::Chat {
+                timestamp,
+                username: username.to_string(),
+                body: body.to_string(),
+            },
+            _ => unknown(),
+        }
+    }
+
+    pub fn to_plain(&self) -> String {
+        match self {
+            NetChatMessage::Chat {
+                timestamp,
+                username,
+                body,
+            } => format!(
+                "[{}] {username}: {body}",
+                timestamp.format(TIMESTAMP_FORMAT)
+            ),
+            NetChatMessage::Unknown(raw_message) => raw_message.to_string(),
+        }
+    }
+
+    /// Returns `None` for messages that have no structure worth formatting.
+    pub fn to_html(&self) -> Option<String> {
+        match self {
+            NetChatMessage::Chat {
+                timestamp,
+                username,
+                body,
+            } => Some(format!(
+                "<b>[{}] {}:</b> {}",
+                timestamp.format(TIMESTAMP_FORMAT),
+                escape_html(username),
+                escape_html(body).replace('\n', "<br>")
+            )),
+            NetChatMessage::Unknown(_) => None,
+        }
+    }
+
+    /// Unknown messages only have their raw text as the body.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            NetChatMessage::Chat {
+                timestamp,
+                username,
+                body,
+            } => serde_json::json!({
+                "timestamp": timestamp.format("%Y-%m-%dT%H:%M:%S").to_string(),
+                "username": username,
+                "body": body,
+            }),
+            NetChatMessage::Unknown(raw_message) => serde_json::json!({
+                "timestamp": null,
+                "username": null,
+                "body": raw_message,
+            }),
         }
-        Err(error) => Err(format!("failed to send GET request: {error}")),
     }
 }
 
-pub async fn get_room_message_count(
-    bot_configuration: &Configuration,
-    name: &str,
-    password: &str,
-) -> Result<usize, String> {
-    match make_request(
-        &bot_configuration,
-        &format!("{NETCHAT_INSTANCE}/{password}/{name}/messageCount"),
-    )
-    .await
-    {
-        Ok(response) => {
-            if response.status().is_server_error() {
-                Err(format!(
-                    "encountered server error while fetching message count"
-                ))
-            } else if response.status() == 429 {
-                Err(format!(
-                    "encountered ratelimit while fetching message count"
-                ))
-            } else if response.status() == 401 {
-                Err(format!("unauthorized while fetching message count"))
-            } else {
-                match response.text().await {
-                    Ok(text) => match text.parse() {
-                        Ok(message_count) => Ok(message_count),
-                        Err(error) => Err(format!(
-                            "failed to deserialize response while fetching message count: {error}"
-                        )),
-                    },
-                    Err(error) => Err(format!(
-                        "failed to get response text while fetching message count: {error}"
-                    )),
-                }
-            }
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// One JSON object (timestamp, username, body) per line.
+    Jsonl,
+    /// The messages as NetChat shows them, one per line.
+    Text,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Text => "txt",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "application/jsonl",
+            ExportFormat::Text => "text/plain",
         }
-        Err(error) => Err(format!("failed to send GET request: {error}")),
     }
 }
 
-pub async fn get_room_messages(
-    bot_configuration: &Configuration,
-    name: &str,
-    password: &str,
-) -> Result<Vec<String>, String> {
-    match make_request(
-        &bot_configuration,
-        &format!("{NETCHAT_INSTANCE}/{password}/{name}/rawMessages"),
-    )
-    .await
-    {
-        Ok(response) => {
-            if response.status().is_server_error() {
-                Err(format!("encountered server error while fetching messages"))
-            } else if response.status() == 429 {
-                Err(format!("encountered ratelimit while fetching messages"))
-            } else if response.status() == 401 {
-                Err(format!("unauthorized while fetching messages"))
-            } else {
-                match response.text().await {
-                    Ok(text) => match serde_json::from_str(&text) {
-                        Ok(raw_messages) => Ok(raw_messages),
-                        Err(error) => Err(format!(
-                            "failed to deserialize response while fetching messages: {error}"
-                        )),
-                    },
-                    Err(error) => Err(format!(
-                        "failed to get response text while fetching messages: {error}"
-                    )),
-                }
+pub fn export_messages(raw_messages: &[String], format: ExportFormat) -> String {
+    let mut output = String::new();
+    for raw_message in raw_messages {
+        let message = NetChatMessage::parse(raw_message);
+        match format {
+            ExportFormat::Jsonl => output.push_str(&message.to_json().to_string()),
+            ExportFormat::Text => output.push_str(&message.to_plain()),
+        }
+        output.push('\n');
+    }
+    output
+}
+
+#[derive(Debug)]
+pub enum NetChatError {
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    /// The request wasn't sent because of earlier ratelimits or server errors.
+    BackingOff(Duration),
+    Unauthorized,
+    Server(reqwest::StatusCode),
+    Decode(String),
+    Transport(reqwest::Error),
+}
+
+impl std::fmt::Display for NetChatError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            NetChatError::RateLimited { retry_after } => match retry_after {
+                Some(retry_after) => write!(
+                    formatter,
+                    "encountered ratelimit (retry after {}s)",
+                    retry_after.as_secs()
+                ),
+                None => write!(formatter, "encountered ratelimit"),
+            },
+            NetChatError::BackingOff(remaining) => write!(
+                formatter,
+                "backing off for {}s after repeated errors",
+                remaining.as_secs()
+            ),
+            NetChatError::Unauthorized => write!(formatter, "unauthorized"),
+            NetChatError::Server(status) => {
+                write!(formatter, "encountered server error ({status})")
             }
+            NetChatError::Decode(error) => write!(formatter, "failed to decode response: {error}"),
+            NetChatError::Transport(error) => write!(formatter, "failed to send request: {error}"),
         }
-        Err(error) => Err(format!("failed to send GET request: {error}")),
     }
 }
 
-pub async fn send_message(
-    bot_configuration: &Configuration,
-    name: &str,
-    password: &str,
-    username: &str,
-    message: &str,
-) -> Result<(), String> {
-    let substitutions = vec![
-        ("#", "||HAS||"),
-        ("%", "||PER||"),
-        ("&", "||AMP||"),
-        ("/", "||SLA||"),
-        ("?", "||QUE||"),
-        ("\\", "||RSLA||"),
-        ("\n", "||NEWL||"),
-    ];
-    let mut formatted_username = username.to_string();
-    let mut formatted_message = message.to_string();
-    for substitution in substitutions {
-        formatted_username = formatted_username.replace(substitution.0, substitution.1);
-        formatted_message = formatted_message.replace(substitution.0, substitution.1);
-    }
-    match make_request(&bot_configuration, &format!(
-        "{NETCHAT_INSTANCE}/{password}/{name}/:FFFFFF/:000000/send/{formatted_username}/{formatted_message}"
-    ))
-    .await
-    {
-        Ok(response) => {
-            if response.status().is_server_error() {
-                Err(format!("encountered server error while sending message"))
-            } else if response.status() == 429 {
-                Err(format!("encountered ratelimit while sending message"))
-            } else if response.status() == 401 {
-                Err(format!("unauthorized while sending message"))
-            } else {
-                Ok(())
+impl std::error::Error for NetChatError {}
+
+#[derive(Default)]
+struct Backoff {
+    failures: u32,
+    until: Option<Instant>,
+}
+
+impl Backoff {
+    fn remaining(&self) -> Option<Duration> {
+        self.until
+            .and_then(|until| until.checked_duration_since(Instant::now()))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    /// Doubles the delay with every consecutive failure, unless
+    /// the server asked for a longer one.
+    fn fail(&mut self, retry_after: Option<Duration>) {
+        self.failures += 1;
+        let delay = BACKOFF_BASE
+            .saturating_mul(1 << (self.failures - 1).min(16))
+            .min(BACKOFF_MAX)
+            .max(retry_after.unwrap_or_default());
+        self.until = Some(Instant::now() + delay);
+    }
+}
+
+/// Backoff state shared by every client cloned from the same [`NetChatClient::new`].
+/// Ratelimits apply to a whole instance, while server errors only hold back
+/// requests to the room that caused them.
+#[derive(Default)]
+struct RateLimiter {
+    instances: HashMap<String, Backoff>,
+    rooms: HashMap<(String, String), Backoff>,
+}
+
+pub struct BackoffState {
+    pub remaining: Duration,
+    pub failures: u32,
+}
+
+#[derive(Clone)]
+pub struct NetChatClient {
+    client: reqwest::Client,
+    instance_url: String,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+}
+
+impl NetChatClient {
+    pub fn new(bot_configuration: &Configuration) -> Result<Self, NetChatError> {
+        let client = match reqwest::ClientBuilder::new()
+            .timeout(Duration::from_secs(bot_configuration.request_timeout))
+            .user_agent(format!(
+                "netchat_bridge/{} (id:{}) reqwest",
+                env!("CARGO_PKG_VERSION"),
+                *NETCHAT_SESSION_ID
+            ))
+            .build()
+        {
+            Ok(client) => client,
+            Err(error) => return Err(NetChatError::Transport(error)),
+        };
+        Ok(Self {
+            client,
+            instance_url: bot_configuration
+                .instance_url
+                .trim_end_matches('/')
+                .to_string(),
+            rate_limiter: Arc::new(Mutex::new(RateLimiter::default())),
+        })
+    }
+
+    /// Returns a client for another NetChat instance that
+    /// shares this client's connection pool and rate limiter.
+    pub fn with_instance_url(&self, instance_url: &str) -> Self {
+        Self {
+            client: self.client.clone(),
+            instance_url: instance_url.trim_end_matches('/').to_string(),
+            rate_limiter: self.rate_limiter.clone(),
+        }
+    }
+
+    /// How long requests to a room are being held back, if at all.
+    pub fn backoff_state(&self, name: &str) -> Option<BackoffState> {
+        let rate_limiter = self.rate_limiter.lock().unwrap();
+        [
+            rate_limiter.instances.get(&self.instance_url),
+            rate_limiter
+                .rooms
+                .get(&(self.instance_url.to_string(), name.to_string())),
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(|backoff| {
+            backoff.remaining().map(|remaining| BackoffState {
+                remaining,
+                failures: backoff.failures,
+            })
+        })
+        .max_by_key(|backoff_state| backoff_state.remaining)
+    }
+
+    async fn request(&self, name: &str, path: &str) -> Result<reqwest::Response, NetChatError> {
+        if let Some(backoff_state) = self.backoff_state(name) {
+            return Err(NetChatError::BackingOff(backoff_state.remaining));
+        }
+        let response = match self
+            .client
+            .get(format!("{}/{path}", self.instance_url))
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            // the URL contains the room password, which mustn't end up in messages or logs
+            Err(error) => return Err(NetChatError::Transport(error.without_url())),
+        };
+        let status = response.status();
+        let mut rate_limiter = self.rate_limiter.lock().unwrap();
+        let room = (self.instance_url.to_string(), name.to_string());
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .map(Duration::from_secs);
+            rate_limiter
+                .instances
+                .entry(self.instance_url.to_string())
+                .or_default()
+                .fail(retry_after);
+            return Err(NetChatError::RateLimited { retry_after });
+        } else if status.is_server_error() {
+            rate_limiter.rooms.entry(room).or_default().fail(None);
+            return Err(NetChatError::Server(status));
+        }
+        rate_limiter.instances.remove(&self.instance_url);
+        rate_limiter.rooms.remove(&room);
+        if status == reqwest::StatusCode::UNAUTHORIZED {
+            Err(NetChatError::Unauthorized)
+        } else {
+            Ok(response)
+        }
+    }
+
+    async fn request_text(&self, name: &str, path: &str) -> Result<String, NetChatError> {
+        match self.request(name, path).await?.text().await {
+            Ok(text) => Ok(text),
+            Err(error) => Err(NetChatError::Decode(error.without_url().to_string())),
+        }
+    }
+
+    pub async fn get_room(&self, name: &str, password: &str) -> Result<String, NetChatError> {
+        self.request_text(name, &format!("{password}/{name}/allMessages"))
+            .await
+    }
+
+    pub async fn get_room_message_count(
+        &self,
+        name: &str,
+        password: &str,
+    ) -> Result<usize, NetChatError> {
+        let text = self
+            .request_text(name, &format!("{password}/{name}/messageCount"))
+            .await?;
+        match text.trim().parse() {
+            Ok(message_count) => Ok(message_count),
+            Err(error) => Err(NetChatError::Decode(error.to_string())),
+        }
+    }
+
+    pub async fn get_room_messages(
+        &self,
+        name: &str,
+        password: &str,
+    ) -> Result<Vec<String>, NetChatError> {
+        let text = self
+            .request_text(name, &format!("{password}/{name}/rawMessages"))
+            .await?;
+        match serde_json::from_str(&text) {
+            Ok(raw_messages) => Ok(raw_messages),
+            Err(error) => Err(NetChatError::Decode(error.to_string())),
+        }
+    }
+
+    pub async fn send_message(
+        &self,
+        name: &str,
+        password: &str,
+        username: &str,
+        message: &str,
+    ) -> Result<(), NetChatError> {
+        let substitutions = [
+            ("#", "||HAS||"),
+            ("%", "||PER||"),
+            ("&", "||AMP||"),
+            ("/", "||SLA||"),
+            ("?", "||QUE||"),
+            ("\\", "||RSLA||"),
+            ("\n", "||NEWL||"),
+        ];
+        let mut formatted_username = username.to_string();
+        let mut formatted_message = message.to_string();
+        for substitution in substitutions {
+            formatted_username = formatted_username.replace(substitution.0, substitution.1);
+            formatted_message = formatted_message.replace(substitution.0, substitution.1);
+        }
+        self.request(
+            name,
+            &format!(
+                "{password}/{name}/:FFFFFF/:000000/send/{formatted_username}/{formatted_message}"
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_netchat::{MockFailure, MockNetChat};
+
+    fn client_for(mock: &MockNetChat) -> NetChatClient {
+        NetChatClient::new(&Configuration {
+            instance_url: mock.url.to_string(),
+            ..Configuration::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_chat_messages() {
+        assert_eq!(
+            NetChatMessage::parse("[2023-09-01 12:34:56] alice: hi: there"),
+            NetChatMessage::Chat {
+                timestamp: NaiveDateTime::parse_from_str("2023-09-01 12:34:56", TIMESTAMP_FORMAT)
+                    .unwrap(),
+                username: "alice".to_string(),
+                body: "hi: there".to_string(),
             }
+        );
+    }
+
+    #[test]
+    fn exports_messages() {
+        let raw_messages = [
+            "[2023-09-01 12:34:56] alice: hi\nthere".to_string(),
+            "server restarted".to_string(),
+        ];
+        assert_eq!(
+            export_messages(&raw_messages, ExportFormat::Jsonl),
+            concat!(
+                r#"{"body":"hi\nthere","timestamp":"2023-09-01T12:34:56","username":"alice"}"#,
+                "\n",
+                r#"{"body":"server restarted","timestamp":null,"username":null}"#,
+                "\n"
+            )
+        );
+        assert_eq!(
+            export_messages(&raw_messages, ExportFormat::Text),
+            "[2023-09-01 12:34:56] alice: hi\nthere\nserver restarted\n"
+        );
+    }
+
+    #[test]
+    fn keeps_unparseable_messages() {
+        for raw_message in ["", "[", "[not a date] a: b", "[2023-09-01 12:34:56]", "é[]"] {
+            assert_eq!(
+                NetChatMessage::parse(raw_message),
+                NetChatMessage::Unknown(raw_message.to_string())
+            );
         }
-        Err(error) => Err(format!("failed to send GET request: {error}")),
+    }
+
+    #[tokio::test]
+    async fn sends_and_fetches_messages() {
+        let mock = MockNetChat::start().await;
+        mock.add_room("room", "password");
+        let client = client_for(&mock);
+
+        let message = "50% of #1 & a/b? c\\d\nsecond line ✓";
+        client
+            .send_message("room", "password", "bob/bot", message)
+            .await
+            .unwrap();
+        assert_eq!(
+            client
+                .get_room_message_count("room", "password")
+                .await
+                .unwrap(),
+            1
+        );
+        let room_messages = client.get_room_messages("room", "password").await.unwrap();
+        match NetChatMessage::parse(&room_messages[0]) {
+            NetChatMessage::Chat { username, body, .. } => {
+                assert_eq!(username, "bob/bot");
+                assert_eq!(body, message);
+            }
+            NetChatMessage::Unknown(raw_message) => panic!("unparsed message {raw_message}"),
+        }
+        assert_eq!(mock.messages("room"), room_messages);
+    }
+
+    #[tokio::test]
+    async fn maps_error_responses() {
+        let mock = MockNetChat::start().await;
+        mock.add_room("room", "password");
+        let client = client_for(&mock);
+
+        assert!(matches!(
+            client.get_room("room", "wrong").await,
+            Err(NetChatError::Unauthorized)
+        ));
+        // every client gets its own rate limiter, so that the
+        // backoff from one error doesn't hide the next one
+        mock.fail_with(Some(MockFailure::RateLimited {
+            retry_after: Some(7),
+        }));
+        assert!(matches!(
+            client_for(&mock).get_room_message_count("room", "password").await,
+            Err(NetChatError::RateLimited {
+                retry_after: Some(retry_after)
+            }) if retry_after == Duration::from_secs(7)
+        ));
+        mock.fail_with(Some(MockFailure::ServerError));
+        assert!(matches!(
+            client_for(&mock)
+                .get_room_messages("room", "password")
+                .await,
+            Err(NetChatError::Server(_))
+        ));
+        mock.fail_with(Some(MockFailure::Unauthorized));
+        assert!(matches!(
+            client.send_message("room", "password", "a", "b").await,
+            Err(NetChatError::Unauthorized)
+        ));
+        mock.fail_with(None);
+        assert!(client.get_room("room", "password").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn backs_off_after_errors() {
+        let mock = MockNetChat::start().await;
+        mock.add_room("room", "password");
+        mock.add_room("other", "password");
+        let client = client_for(&mock);
+
+        mock.fail_with(Some(MockFailure::ServerError));
+        assert!(client.get_room("room", "password").await.is_err());
+        let requests = mock.request_count();
+        assert!(matches!(
+            client.get_room("room", "password").await,
+            Err(NetChatError::BackingOff(_))
+        ));
+        assert_eq!(mock.request_count(), requests);
+        assert_eq!(client.backoff_state("room").unwrap().failures, 1);
+
+        // server errors only hold back the room that caused them...
+        mock.fail_with(None);
+        assert!(client.get_room("other", "password").await.is_ok());
+
+        // ...while ratelimits apply to the whole instance
+        mock.fail_with(Some(MockFailure::RateLimited {
+            retry_after: Some(60),
+        }));
+        assert!(client.get_room("other", "password").await.is_err());
+        let backoff_state = client
+            .with_instance_url(&mock.url)
+            .backoff_state("third")
+            .unwrap();
+        assert!(backoff_state.remaining > Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn keeps_passwords_out_of_errors() {
+        // nothing listens on port 1, so the connection is refused
+        let client = NetChatClient::new(&Configuration {
+            instance_url: "http://127.0.0.1:1".to_string(),
+            ..Configuration::default()
+        })
+        .unwrap();
+        let error = client
+            .get_room_message_count("room", "hunter2")
+            .await
+            .unwrap_err();
+        assert!(matches!(error, NetChatError::Transport(_)));
+        assert!(!error.to_string().contains("hunter2"));
     }
 }
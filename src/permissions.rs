@@ -1,8 +1,12 @@
 use crate::commands::CommandInput;
 
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy)]
 pub enum Action {
     BridgeCreate,
     BridgeDestroy,
+    BridgeConfigure,
+    BridgeExport,
 }
 
 #[derive(PartialEq, PartialOrd)]
@@ -23,14 +27,10 @@ impl PowerLevelConstraint {
     }
 
     fn is_allowed(&self, power_level: i64) -> bool {
-        if self.maximum.is_none() {
-            return power_level >= self.minimum;
-        } else {
-            if self.minimum == self.maximum.unwrap() {
-                return power_level == self.minimum;
-            } else {
-                return self.maximum.unwrap() >= power_level && power_level >= self.minimum;
-            }
+        match self.maximum {
+            None => power_level >= self.minimum,
+            Some(maximum) if maximum == self.minimum => power_level == self.minimum,
+            Some(maximum) => maximum >= power_level && power_level >= self.minimum,
         }
     }
 }
@@ -39,6 +39,10 @@ pub fn get_power_level_constraint(action: Action) -> PowerLevelConstraint {
     match action {
         Action::BridgeCreate => PowerLevelConstraint::new(PowerLevel::Administrator as i64, None),
         Action::BridgeDestroy => PowerLevelConstraint::new(PowerLevel::Administrator as i64, None),
+        Action::BridgeConfigure => {
+            PowerLevelConstraint::new(PowerLevel::Administrator as i64, None)
+        }
+        Action::BridgeExport => PowerLevelConstraint::new(PowerLevel::Administrator as i64, None),
     }
 }
 
@@ -5,7 +5,16 @@ use std::path::Path;
 pub struct Secrets {
     pub homeserver_url: String,
     pub username: String,
+    #[serde(default)]
     pub password: String,
+    #[serde(default)]
+    pub store_passphrase: Option<String>,
+    /// A base64-encoded key used to encrypt NetChat room passwords
+    /// in the database. Takes precedence over `database_key_file`.
+    #[serde(default)]
+    pub database_key: Option<String>,
+    #[serde(default)]
+    pub database_key_file: Option<String>,
 }
 
 impl Secrets {
@@ -14,7 +23,7 @@ impl Secrets {
             Ok(file_contents) => file_contents,
             Err(error) => {
                 return match error.kind() {
-                    std::io::ErrorKind::NotFound => Err(format!("file not found")),
+                    std::io::ErrorKind::NotFound => Err("file not found".to_string()),
                     _ => Err(format!("unable to read file: {error}")),
                 }
             }
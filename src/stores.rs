@@ -0,0 +1,600 @@
+//! Typed access to the records in the database, so that callers
+//! don't have to build keys or (de)serialize values themselves.
+
+use crate::{
+    database::Database,
+    encryption::{self, DatabaseKey},
+    BridgedRoomData, MatrixBridgeMessage,
+};
+use matrix_sdk::Session;
+
+#[derive(Debug)]
+pub enum StoreError {
+    Database(String),
+    /// A stored value couldn't be parsed.
+    Corrupted(serde_json::Error),
+    /// An encrypted value couldn't be decrypted.
+    Encryption(String),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StoreError::Database(error) => write!(formatter, "database error: {error}"),
+            StoreError::Corrupted(error) => write!(formatter, "corrupted record: {error}"),
+            StoreError::Encryption(error) => write!(formatter, "encryption error: {error}"),
+        }
+    }
+}
+
+/// Bridges, stored as `bridge.{matrix_room_id}`. With a key,
+/// room passwords are encrypted before they are written.
+#[derive(Clone)]
+pub struct BridgeStore {
+    database: Database,
+    key: Option<DatabaseKey>,
+}
+
+impl BridgeStore {
+    pub fn new(database: &Database, key: Option<DatabaseKey>) -> Self {
+        Self {
+            database: database.clone(),
+            key,
+        }
+    }
+
+    fn key(matrix_room_id: &str) -> String {
+        format!("bridge.{matrix_room_id}")
+    }
+
+    fn serialize(&self, matrix_room_id: &str, bridged_room_data: &BridgedRoomData) -> String {
+        match &self.key {
+            Some(key) => serde_json::to_string(&BridgedRoomData {
+                room_password: key.encrypt(&bridged_room_data.room_password, matrix_room_id),
+                ..bridged_room_data.clone()
+            })
+            .unwrap(),
+            None => serde_json::to_string(bridged_room_data).unwrap(),
+        }
+    }
+
+    fn deserialize(
+        &self,
+        matrix_room_id: &str,
+        value: &str,
+    ) -> Result<BridgedRoomData, StoreError> {
+        let mut bridged_room_data: BridgedRoomData = match serde_json::from_str(value) {
+            Ok(bridged_room_data) => bridged_room_data,
+            Err(error) => return Err(StoreError::Corrupted(error)),
+        };
+        if encryption::is_encrypted(&bridged_room_data.room_password) {
+            bridged_room_data.room_password = match &self.key {
+                Some(key) => key
+                    .decrypt(&bridged_room_data.room_password, matrix_room_id)
+                    .map_err(StoreError::Encryption)?,
+                None => {
+                    return Err(StoreError::Encryption(
+                        "room password is encrypted, but no database key is configured".to_string(),
+                    ))
+                }
+            };
+        }
+        Ok(bridged_room_data)
+    }
+
+    pub fn get(&self, matrix_room_id: &str) -> Result<Option<BridgedRoomData>, StoreError> {
+        match self.database.get(&Self::key(matrix_room_id)) {
+            Ok(Some(value)) => self.deserialize(matrix_room_id, &value).map(Some),
+            Ok(None) => Ok(None),
+            Err(error) => Err(StoreError::Database(error)),
+        }
+    }
+
+    pub fn set(
+        &self,
+        matrix_room_id: &str,
+        bridged_room_data: &BridgedRoomData,
+    ) -> Result<(), StoreError> {
+        self.database
+            .set(
+                &Self::key(matrix_room_id),
+                &self.serialize(matrix_room_id, bridged_room_data),
+            )
+            .map_err(StoreError::Database)
+    }
+
+    pub fn remove(&self, matrix_room_id: &str) -> Result<(), StoreError> {
+        self.database
+            .remove(&Self::key(matrix_room_id))
+            .map_err(StoreError::Database)
+    }
+
+    /// Changes a bridge without losing concurrent changes to it. `update` may
+    /// be called more than once, so it shouldn't have any side effects.
+    /// Returns the updated bridge, or `None` if the room isn't bridged.
+    pub fn update(
+        &self,
+        matrix_room_id: &str,
+        mut update: impl FnMut(&mut BridgedRoomData),
+    ) -> Result<Option<BridgedRoomData>, StoreError> {
+        let mut result = Ok(None);
+        self.database
+            .update_and_fetch(&Self::key(matrix_room_id), |value| {
+                let value = value?;
+                match self.deserialize(matrix_room_id, value) {
+                    Ok(mut bridged_room_data) => {
+                        update(&mut bridged_room_data);
+                        let value = self.serialize(matrix_room_id, &bridged_room_data);
+                        result = Ok(Some(bridged_room_data));
+                        Some(value)
+                    }
+                    // unreadable records are left as they are
+                    Err(error) => {
+                        result = Err(error);
+                        Some(value.to_string())
+                    }
+                }
+            })
+            .map_err(StoreError::Database)?;
+        result
+    }
+
+    /// Every bridge, keyed by its Matrix room ID.
+    pub fn iter(&self) -> impl Iterator<Item = (String, Result<BridgedRoomData, StoreError>)> + '_ {
+        self.database.scan_prefix("bridge.").map(|(key, value)| {
+            let matrix_room_id = key["bridge.".len()..].to_string();
+            let bridged_room_data = self.deserialize(&matrix_room_id, &value);
+            (matrix_room_id, bridged_room_data)
+        })
+    }
+
+    /// Rewrites the bridges with `new_key` in one go, for example to rotate
+    /// the key. With `only_unencrypted`, bridges with encrypted passwords
+    /// are skipped. Returns how many bridges were rewritten.
+    pub fn reencrypt(
+        &self,
+        new_key: &DatabaseKey,
+        only_unencrypted: bool,
+    ) -> Result<usize, StoreError> {
+        let new_store = BridgeStore::new(&self.database, Some(new_key.clone()));
+        let mut entries = Vec::new();
+        for (key, value) in self.database.scan_prefix("bridge.") {
+            let matrix_room_id = &key["bridge.".len()..];
+            if only_unencrypted
+                && serde_json::from_str::<BridgedRoomData>(&value)
+                    .is_ok_and(|stored| encryption::is_encrypted(&stored.room_password))
+            {
+                continue;
+            }
+            let bridged_room_data = self.deserialize(matrix_room_id, &value)?;
+            entries.push((
+                key.to_string(),
+                new_store.serialize(matrix_room_id, &bridged_room_data),
+            ));
+        }
+        let count = entries.len();
+        self.database
+            .apply_batch(entries)
+            .map_err(StoreError::Database)?;
+        Ok(count)
+    }
+}
+
+/// NetChat usernames chosen by Matrix users, stored per room
+/// as `username.{matrix_room_id}.{matrix_user_id}`.
+#[derive(Clone)]
+pub struct UsernameStore {
+    database: Database,
+}
+
+impl UsernameStore {
+    pub fn new(database: &Database) -> Self {
+        Self {
+            database: database.clone(),
+        }
+    }
+
+    fn key(matrix_room_id: &str, matrix_user_id: &str) -> String {
+        format!("username.{matrix_room_id}.{matrix_user_id}")
+    }
+
+    pub fn get(
+        &self,
+        matrix_room_id: &str,
+        matrix_user_id: &str,
+    ) -> Result<Option<String>, StoreError> {
+        self.database
+            .get(&Self::key(matrix_room_id, matrix_user_id))
+            .map_err(StoreError::Database)
+    }
+
+    pub fn set(
+        &self,
+        matrix_room_id: &str,
+        matrix_user_id: &str,
+        username: &str,
+    ) -> Result<(), StoreError> {
+        self.database
+            .set(&Self::key(matrix_room_id, matrix_user_id), username)
+            .map_err(StoreError::Database)
+    }
+
+    pub fn remove(&self, matrix_room_id: &str, matrix_user_id: &str) -> Result<(), StoreError> {
+        self.database
+            .remove(&Self::key(matrix_room_id, matrix_user_id))
+            .map_err(StoreError::Database)
+    }
+}
+
+/// Matrix messages waiting to be sent to NetChat, stored as
+/// `outbound.{matrix_room_id}.{sequence}` so that they are kept in order.
+#[derive(Clone)]
+pub struct OutboundStore {
+    database: Database,
+}
+
+impl OutboundStore {
+    pub fn new(database: &Database) -> Self {
+        Self {
+            database: database.clone(),
+        }
+    }
+
+    pub fn push(&self, bridge_message: &MatrixBridgeMessage) -> Result<(), StoreError> {
+        let sequence = self.database.generate_id().map_err(StoreError::Database)?;
+        self.database
+            .set(
+                &format!("outbound.{}.{sequence:020}", bridge_message.matrix_room_id),
+                &serde_json::to_string(bridge_message).unwrap(),
+            )
+            .map_err(StoreError::Database)
+    }
+
+    /// Every queued message, oldest first within each room, along
+    /// with the key it can be updated or removed by.
+    pub fn iter(
+        &self,
+    ) -> impl Iterator<Item = (String, Result<MatrixBridgeMessage, StoreError>)> + '_ {
+        self.database.scan_prefix("outbound.").map(|(key, value)| {
+            let bridge_message = serde_json::from_str(&value).map_err(StoreError::Corrupted);
+            (key, bridge_message)
+        })
+    }
+
+    pub fn set(&self, key: &str, bridge_message: &MatrixBridgeMessage) -> Result<(), StoreError> {
+        self.database
+            .set(key, &serde_json::to_string(bridge_message).unwrap())
+            .map_err(StoreError::Database)
+    }
+
+    pub fn remove(&self, key: &str) -> Result<(), StoreError> {
+        self.database.remove(key).map_err(StoreError::Database)
+    }
+
+    /// Whether the message relayed from a Matrix event is still waiting to be sent.
+    pub fn contains_event(&self, matrix_room_id: &str, event_id: &str) -> Result<bool, StoreError> {
+        for (_, bridge_message) in self.iter() {
+            let bridge_message = bridge_message?;
+            if bridge_message.matrix_room_id == matrix_room_id
+                && bridge_message.matrix_event_id.as_deref() == Some(event_id)
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Drops everything that is still queued for a Matrix room.
+    pub fn remove_room(&self, matrix_room_id: &str) -> Result<(), StoreError> {
+        for (key, bridge_message) in self.iter() {
+            // the key prefix alone would also match rooms on longer server names
+            if bridge_message
+                .is_ok_and(|bridge_message| bridge_message.matrix_room_id == matrix_room_id)
+            {
+                self.remove(&key)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Matrix messages that have been delivered to NetChat, so that edits and
+/// redactions of them can be relayed too. Stored with the time they were
+/// delivered as `relayed.{matrix_room_id}.{event_id}`.
+#[derive(Clone)]
+pub struct RelayedStore {
+    database: Database,
+}
+
+impl RelayedStore {
+    pub fn new(database: &Database) -> Self {
+        Self {
+            database: database.clone(),
+        }
+    }
+
+    fn key(matrix_room_id: &str, event_id: &str) -> String {
+        format!("relayed.{matrix_room_id}.{event_id}")
+    }
+
+    pub fn contains(&self, matrix_room_id: &str, event_id: &str) -> Result<bool, StoreError> {
+        match self.database.get(&Self::key(matrix_room_id, event_id)) {
+            Ok(value) => Ok(value.is_some()),
+            Err(error) => Err(StoreError::Database(error)),
+        }
+    }
+
+    pub fn insert(
+        &self,
+        matrix_room_id: &str,
+        event_id: &str,
+        relayed_at: i64,
+    ) -> Result<(), StoreError> {
+        self.database
+            .set(
+                &Self::key(matrix_room_id, event_id),
+                &relayed_at.to_string(),
+            )
+            .map_err(StoreError::Database)
+    }
+
+    pub fn remove(&self, matrix_room_id: &str, event_id: &str) -> Result<(), StoreError> {
+        self.database
+            .remove(&Self::key(matrix_room_id, event_id))
+            .map_err(StoreError::Database)
+    }
+
+    pub fn remove_room(&self, matrix_room_id: &str) -> Result<(), StoreError> {
+        // event IDs start with `$`, which keeps rooms on longer server names out
+        for (key, _) in self.database.scan_prefix(&Self::key(matrix_room_id, "$")) {
+            self.database.remove(&key).map_err(StoreError::Database)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the messages relayed before `oldest`. Returns how many were removed.
+    pub fn prune(&self, oldest: i64) -> Result<usize, StoreError> {
+        let mut count = 0;
+        for (key, value) in self.database.scan_prefix("relayed.") {
+            // older records only stored the username, not when they were relayed
+            if value
+                .parse::<i64>()
+                .is_ok_and(|relayed_at| relayed_at >= oldest)
+            {
+                continue;
+            }
+            self.database.remove(&key).map_err(StoreError::Database)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+/// The Matrix session of the bot, stored as `session` so that
+/// it doesn't have to log in with its password on every start.
+#[derive(Clone)]
+pub struct SessionStore {
+    database: Database,
+}
+
+impl SessionStore {
+    const KEY: &'static str = "session";
+
+    pub fn new(database: &Database) -> Self {
+        Self {
+            database: database.clone(),
+        }
+    }
+
+    pub fn get(&self) -> Result<Option<Session>, StoreError> {
+        match self.database.get(Self::KEY) {
+            Ok(Some(value)) => serde_json::from_str(&value)
+                .map(Some)
+                .map_err(StoreError::Corrupted),
+            Ok(None) => Ok(None),
+            Err(error) => Err(StoreError::Database(error)),
+        }
+    }
+
+    pub fn set(&self, session: &Session) -> Result<(), StoreError> {
+        self.database
+            .set(Self::KEY, &serde_json::to_string(session).unwrap())
+            .map_err(StoreError::Database)
+    }
+
+    pub fn remove(&self) -> Result<(), StoreError> {
+        self.database
+            .remove(Self::KEY)
+            .map_err(StoreError::Database)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bridged_room_data() -> BridgedRoomData {
+        serde_json::from_str(r#"{"room_name":"lobby","room_password":"hunter2","message_count":0}"#)
+            .unwrap()
+    }
+
+    #[test]
+    fn updates_bridges_atomically() {
+        let bridges = BridgeStore::new(&Database::temporary(), None);
+        assert!(bridges
+            .update("!a:example.org", |_| unreachable!())
+            .unwrap()
+            .is_none());
+
+        bridges.set("!a:example.org", &bridged_room_data()).unwrap();
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let bridges = bridges.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        bridges
+                            .update("!a:example.org", |bridged_room_data| {
+                                bridged_room_data.message_count += 1
+                            })
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(
+            bridges
+                .get("!a:example.org")
+                .unwrap()
+                .unwrap()
+                .message_count,
+            400
+        );
+        assert_eq!(bridges.iter().count(), 1);
+    }
+
+    #[test]
+    fn reports_corrupted_bridges() {
+        let database = Database::temporary();
+        database.set("bridge.!a:example.org", "{").unwrap();
+        let bridges = BridgeStore::new(&database, None);
+        assert!(matches!(
+            bridges.get("!a:example.org"),
+            Err(StoreError::Corrupted(_))
+        ));
+        assert!(matches!(
+            bridges.update("!a:example.org", |_| ()),
+            Err(StoreError::Corrupted(_))
+        ));
+        assert_eq!(
+            database.get("bridge.!a:example.org").unwrap().as_deref(),
+            Some("{")
+        );
+    }
+
+    #[test]
+    fn encrypts_room_passwords() {
+        let database = Database::temporary();
+        BridgeStore::new(&database, None)
+            .set("!a:example.org", &bridged_room_data())
+            .unwrap();
+        let key = DatabaseKey::generate();
+        let bridges = BridgeStore::new(&database, Some(key.clone()));
+        assert_eq!(bridges.reencrypt(&key, true).unwrap(), 1);
+        assert_eq!(bridges.reencrypt(&key, true).unwrap(), 0);
+        assert!(!database
+            .get("bridge.!a:example.org")
+            .unwrap()
+            .unwrap()
+            .contains("hunter2"));
+        assert_eq!(
+            bridges
+                .get("!a:example.org")
+                .unwrap()
+                .unwrap()
+                .room_password,
+            "hunter2"
+        );
+        assert!(matches!(
+            BridgeStore::new(&database, None).get("!a:example.org"),
+            Err(StoreError::Encryption(_))
+        ));
+
+        let new_key = DatabaseKey::generate();
+        assert_eq!(bridges.reencrypt(&new_key, false).unwrap(), 1);
+        assert!(bridges.get("!a:example.org").is_err());
+        assert_eq!(
+            BridgeStore::new(&database, Some(new_key))
+                .get("!a:example.org")
+                .unwrap()
+                .unwrap()
+                .room_password,
+            "hunter2"
+        );
+    }
+
+    fn bridge_message(matrix_room_id: &str, body: &str) -> MatrixBridgeMessage {
+        MatrixBridgeMessage {
+            matrix_room_id: matrix_room_id.to_string(),
+            matrix_sender: "@alice:example.org".to_string(),
+            netchat_username: "alice".to_string(),
+            netchat_message: body.to_string(),
+            attempts: 0,
+            next_attempt_at: 0,
+            matrix_event_id: None,
+            edited_event_id: None,
+        }
+    }
+
+    #[test]
+    fn queues_outbound_messages_per_room() {
+        let outbound = OutboundStore::new(&Database::temporary());
+        for body in ["first", "second"] {
+            outbound
+                .push(&bridge_message("!a:example.org", body))
+                .unwrap();
+        }
+        outbound
+            .push(&bridge_message("!a:example.org.uk", "other"))
+            .unwrap();
+
+        let mut queued_event = bridge_message("!a:example.org", "third");
+        queued_event.matrix_event_id = Some("$third".to_string());
+        outbound.push(&queued_event).unwrap();
+        assert!(outbound.contains_event("!a:example.org", "$third").unwrap());
+        assert!(!outbound
+            .contains_event("!a:example.org.uk", "$third")
+            .unwrap());
+
+        outbound.remove_room("!a:example.org").unwrap();
+        let queued: Vec<_> = outbound
+            .iter()
+            .map(|(_, bridge_message)| bridge_message.unwrap().netchat_message)
+            .collect();
+        assert_eq!(queued, ["other"]);
+    }
+
+    #[test]
+    fn prunes_old_relayed_messages() {
+        let database = Database::temporary();
+        let relayed = RelayedStore::new(&database);
+        relayed.insert("!a:example.org", "$new", 100).unwrap();
+        relayed.insert("!a:example.org", "$old", 99).unwrap();
+        database
+            .set("relayed.!a:example.org.$legacy", "alice")
+            .unwrap();
+        relayed.insert("!a:example.org.uk", "$other", 100).unwrap();
+
+        assert_eq!(relayed.prune(100).unwrap(), 2);
+        assert!(relayed.contains("!a:example.org", "$new").unwrap());
+        assert!(!relayed.contains("!a:example.org", "$old").unwrap());
+        relayed.remove_room("!a:example.org").unwrap();
+        assert!(!relayed.contains("!a:example.org", "$new").unwrap());
+        assert!(relayed.contains("!a:example.org.uk", "$other").unwrap());
+    }
+
+    #[test]
+    fn keeps_usernames_per_room() {
+        let usernames = UsernameStore::new(&Database::temporary());
+        usernames
+            .set("!a:example.org", "@alice:example.org", "alice")
+            .unwrap();
+        assert_eq!(
+            usernames
+                .get("!b:example.org", "@alice:example.org")
+                .unwrap(),
+            None
+        );
+        usernames
+            .remove("!a:example.org", "@alice:example.org")
+            .unwrap();
+        assert_eq!(
+            usernames
+                .get("!a:example.org", "@alice:example.org")
+                .unwrap(),
+            None
+        );
+    }
+}
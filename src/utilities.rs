@@ -3,8 +3,15 @@ use crate::{
     language::get_text,
     logging::{log_error, log_matrix_error, log_message},
     permissions::{self, Action},
+    stores::StoreError,
+};
+use matrix_sdk::{
+    room,
+    ruma::{
+        events::room::{message::RoomMessageEventContent, MediaSource},
+        UInt,
+    },
 };
-use matrix_sdk::{room, ruma::events::room::message::RoomMessageEventContent};
 
 fn has_html(string: &str) -> bool {
     let html_tags = ["b", "code"];
@@ -16,8 +23,99 @@ fn has_html(string: &str) -> bool {
     false
 }
 
+pub fn escape_html(string: &str) -> String {
+    string
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Removes the quoted `> <@user:server> ...` block that Matrix
+/// clients prepend to the plain-text body of replies.
+pub fn strip_reply_fallback(body: &str) -> &str {
+    let mut rest = body;
+    while rest.starts_with('>') {
+        match rest.split_once('\n') {
+            Some((_, next_lines)) => rest = next_lines,
+            None => return "",
+        }
+    }
+    rest.strip_prefix('\n').unwrap_or(rest)
+}
+
+pub fn format_reply_quote(name: &str, text: &str) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut quote: String = text.chars().take(40).collect();
+    if quote.len() < text.len() {
+        quote.push('…');
+    }
+    format!("↪ replying to {name}: \"{quote}\"")
+}
+
+pub fn percent_decode(string: &str) -> String {
+    let bytes = string.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            if let Some(Ok(byte)) = string
+                .get(index + 1..index + 3)
+                .map(|hex| u8::from_str_radix(hex, 16))
+            {
+                decoded.push(byte);
+                index += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[index]);
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+pub fn format_file_size(size: u64) -> String {
+    let units = ["KB", "MB", "GB"];
+    if size < 1024 {
+        return format!("{size} B");
+    }
+    let mut size = size as f64 / 1024.0;
+    for unit in &units[..units.len() - 1] {
+        if size < 1024.0 {
+            return format!("{size:.1} {unit}");
+        }
+        size /= 1024.0;
+    }
+    format!("{size:.1} {}", units[units.len() - 1])
+}
+
+/// Describes a Matrix attachment as text for NetChat, linking to
+/// the homeserver's media repository when the file isn't encrypted.
+pub fn describe_attachment(
+    kind: &str,
+    name: &str,
+    source: &MediaSource,
+    size: Option<UInt>,
+    homeserver: &reqwest::Url,
+) -> String {
+    let label = match size {
+        Some(size) => format!("[{kind}: {name} ({})]", format_file_size(size.into())),
+        None => format!("[{kind}: {name}]"),
+    };
+    match source {
+        MediaSource::Plain(mxc_uri) => match mxc_uri.parts() {
+            Ok((server_name, media_id)) => format!(
+                "{label} {}/_matrix/media/v3/download/{server_name}/{media_id}",
+                homeserver.as_str().trim_end_matches('/')
+            ),
+            Err(_) => label,
+        },
+        MediaSource::Encrypted(_) => format!("{label} (encrypted)"),
+    }
+}
+
 pub async fn handle_permissions(command_input: &CommandInput, action: Action) -> bool {
-    if !match permissions::is_allowed(&command_input, Action::BridgeCreate).await {
+    if !match permissions::is_allowed(command_input, action).await {
         Ok(is_allowed) => is_allowed,
         Err(error) => {
             log_error(&error);
@@ -32,7 +130,7 @@ pub async fn handle_permissions(command_input: &CommandInput, action: Action) ->
         send_plain_message(
             &command_input.room,
             &get_text("command_no_permissions").replace(
-                "{minimum_level}",
+                "{minimum_power_level}",
                 permissions::get_power_level_constraint(action)
                     .minimum
                     .to_string()
@@ -50,7 +148,7 @@ pub async fn set_typing(room: &room::Joined, typing: bool) {
 }
 
 pub async fn send_plain_message(room: &room::Joined, content: &str) {
-    if has_html(&content) {
+    if has_html(content) {
         log_message(
             crate::logging::LogMessageType::Warning,
             &format!("HTML not used but sent formatted message ({content})",),
@@ -65,7 +163,7 @@ pub async fn send_plain_message(room: &room::Joined, content: &str) {
 }
 
 pub async fn send_html_message(room: &room::Joined, content: &str) {
-    if !has_html(&content) {
+    if !has_html(content) {
         log_message(
             crate::logging::LogMessageType::Warning,
             &format!("HTML not used but sent formatted message ({content})",),
@@ -78,3 +176,84 @@ pub async fn send_html_message(room: &room::Joined, content: &str) {
     );
     set_typing(room, false).await;
 }
+
+pub async fn send_store_error(room: &room::Joined, error: &StoreError) {
+    let content = match error {
+        StoreError::Database(error) | StoreError::Encryption(error) => {
+            get_text("database_error").replace("{error}", error)
+        }
+        StoreError::Corrupted(error) => {
+            get_text("database_possibly_corrupted").replace("{error}", &error.to_string())
+        }
+    };
+    send_html_message(room, &content).await;
+}
+
+pub async fn send_notice(room: &room::Joined, plain: &str, html: Option<&str>) {
+    let content = match html {
+        Some(html) => RoomMessageEventContent::notice_html(plain, html),
+        None => RoomMessageEventContent::notice_plain(plain),
+    };
+    log_matrix_error(room.send(content, None).await);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::{mxc_uri, uint};
+
+    #[test]
+    fn strips_reply_fallbacks() {
+        assert_eq!(
+            strip_reply_fallback("> <@alice:example.org> hello\n> world\n\nhi alice"),
+            "hi alice"
+        );
+        assert_eq!(strip_reply_fallback("no fallback"), "no fallback");
+        assert_eq!(strip_reply_fallback("> only a quote"), "");
+    }
+
+    #[test]
+    fn formats_reply_quotes() {
+        assert_eq!(
+            format_reply_quote("Alice", "short\nmessage"),
+            "↪ replying to Alice: \"short message\""
+        );
+        assert_eq!(
+            format_reply_quote("Bob", &"a".repeat(50)),
+            format!("↪ replying to Bob: \"{}…\"", "a".repeat(40))
+        );
+    }
+
+    #[test]
+    fn formats_file_sizes() {
+        assert_eq!(format_file_size(512), "512 B");
+        assert_eq!(format_file_size(12_595), "12.3 KB");
+        assert_eq!(format_file_size(5 * 1024 * 1024), "5.0 MB");
+        assert_eq!(format_file_size(3 * 1024 * 1024 * 1024 * 1024), "3072.0 GB");
+    }
+
+    #[test]
+    fn describes_attachments() {
+        let homeserver = reqwest::Url::parse("https://matrix.example.org/").unwrap();
+        assert_eq!(
+            describe_attachment(
+                "image",
+                "cat.png",
+                &MediaSource::Plain(mxc_uri!("mxc://example.org/abc123").to_owned()),
+                Some(uint!(2048)),
+                &homeserver,
+            ),
+            "[image: cat.png (2.0 KB)] https://matrix.example.org/_matrix/media/v3/download/example.org/abc123"
+        );
+        assert_eq!(
+            describe_attachment(
+                "file",
+                "notes.txt",
+                &MediaSource::Plain(mxc_uri!("mxc://example.org/def").to_owned()),
+                None,
+                &homeserver,
+            ),
+            "[file: notes.txt] https://matrix.example.org/_matrix/media/v3/download/example.org/def"
+        );
+    }
+}